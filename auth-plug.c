@@ -1,18 +1,33 @@
 #include "mosquitto.h"
 #include "mosquitto_plugin.h"
+#if MOSQ_AUTH_PLUGIN_VERSION >= 3
+#include "mosquitto_broker.h"
+#endif
+
+#define CHIPIN_ERR_SESSION_EXPIRED 100
 
 #if LIBMOSQUITTO_VERSION_NUMBER >= 1004090
 int conv_code(int val){
 	if(val == MOSQ_ERR_AUTH) return MOSQ_ERR_PLUGIN_DEFER;
 	if(val == MOSQ_ERR_ACL_DENIED) return MOSQ_ERR_PLUGIN_DEFER;
+	if(val == CHIPIN_ERR_SESSION_EXPIRED) return MOSQ_ERR_PLUGIN_DEFER;
 	return val;
 }
 #else
 int conv_code(int val){
+	if(val == CHIPIN_ERR_SESSION_EXPIRED) return MOSQ_ERR_ACL_DENIED;
 	return val;
 }
 #endif
 
+void kick_expired(const struct mosquitto *client, int val){
+#if LIBMOSQUITTO_VERSION_NUMBER >= 2000000
+	if(val == CHIPIN_ERR_SESSION_EXPIRED){
+		mosquitto_kick_client_by_clientid(mosquitto_client_id(client), false);
+	}
+#endif
+}
+
 #if MOSQ_AUTH_PLUGIN_VERSION >= 3
 # define mosquitto_auth_opt mosquitto_opt
 #endif
@@ -22,7 +37,7 @@ int proc_mosquitto_auth_plugin_cleanup(void *userdata, struct mosquitto_auth_opt
 int proc_mosquitto_auth_security_init(void *userdata, struct mosquitto_auth_opt *auth_opts, int auth_opt_count, bool reload);
 int proc_mosquitto_auth_security_cleanup(void *userdata, struct mosquitto_auth_opt *auth_opts, int auth_opt_count, bool reload);
 #if MOSQ_AUTH_PLUGIN_VERSION >= 3
-int proc_mosquitto_auth_unpwd_check_v3(void *userdata, const struct mosquitto *client, const char *username, const char *password);
+int proc_mosquitto_auth_unpwd_check_v3(void *userdata, const struct mosquitto *client, const char *clientid, const char *username, const char *password);
 int proc_mosquitto_auth_acl_check_v3(void *userdata, int access, const struct mosquitto *client, const struct mosquitto_acl_msg *msg);
 #else
 int proc_mosquitto_auth_unpwd_check_v2(void *userdata, const char *username, const char *password);
@@ -57,13 +72,13 @@ int mosquitto_auth_security_cleanup(void *userdata, struct mosquitto_auth_opt *a
 #if MOSQ_AUTH_PLUGIN_VERSION >=4
 int mosquitto_auth_unpwd_check(void *userdata, struct mosquitto *client, const char *username, const char *password)
 {
-	int granted = proc_mosquitto_auth_unpwd_check_v3(userdata, client, username, password);
+	int granted = proc_mosquitto_auth_unpwd_check_v3(userdata, client, mosquitto_client_id(client), username, password);
 	return conv_code(granted);
 }
 #elif MOSQ_AUTH_PLUGIN_VERSION >=3
 int mosquitto_auth_unpwd_check(void *userdata, const struct mosquitto *client, const char *username, const char *password)
 {
-	int granted = proc_mosquitto_auth_unpwd_check_v3(userdata, client, username, password);
+	int granted = proc_mosquitto_auth_unpwd_check_v3(userdata, client, mosquitto_client_id(client), username, password);
 	return conv_code(granted);
 }
 #else
@@ -78,12 +93,14 @@ int mosquitto_auth_unpwd_check(void *userdata, const char *username, const char
 int mosquitto_auth_acl_check(void *userdata, int access, struct mosquitto *client, const struct mosquitto_acl_msg *msg)
 {
 	int granted = proc_mosquitto_auth_acl_check_v3(userdata, access, client, msg);
+	kick_expired(client, granted);
 	return conv_code(granted);
 }
 #elif MOSQ_AUTH_PLUGIN_VERSION >= 3
 int mosquitto_auth_acl_check(void *userdata, int access, const struct mosquitto *client, const struct mosquitto_acl_msg *msg)
 {
 	int granted = proc_mosquitto_auth_acl_check_v3(userdata, access, client, msg);
+	kick_expired(client, granted);
 	return conv_code(granted);
 }
 #else
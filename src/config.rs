@@ -1,39 +1,642 @@
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet, PublicKeyUse};
+use jsonwebtoken::{Algorithm, DecodingKey};
 use regex::Regex;
-use serde::de::{self, Deserialize, Deserializer};
+use regex_syntax::hir::{Hir, HirKind, Look};
+use serde::de::{self, Deserialize, Deserializer, MapAccess, Visitor};
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::fs::File;
+use std::fmt;
+use std::fs::{self, File};
 use std::path::Path;
+use std::str::FromStr;
+use topic;
+use DEFAULT_DADGET_TOPICS;
 
 #[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct Config {
-    pub key: String,
+    pub key: Option<String>,
+    #[serde(default)]
+    pub keys: Vec<VerificationKey>,
+    pub algorithms: Option<Vec<Algorithm>>,
+    pub jwks: Option<Jwks>,
+    #[serde(default)]
+    pub validation: ClaimValidation,
+    #[serde(default)]
+    pub session: SessionPolicy,
+    pub revocation: Option<String>,
+    #[serde(skip)]
+    pub revoked: Revocation,
+    pub directory: Option<Directory>,
+    #[serde(default)]
+    pub token_source: TokenSource,
+    /// claim which the username has to be equal to
+    pub username_claim: Option<String>,
+    pub client_id_binding: Option<ClientIdBinding>,
+    /// lets an access of any operation apply to the subscriptions to a resource,
+    /// as before the `SUBSCRIBE` operation was introduced
+    #[serde(default)]
+    pub legacy_subscribe: bool,
+    /// how the effects of the applicable accesses are combined
+    #[serde(default)]
+    pub combining: Combining,
+    /// grants from the OAuth2 scopes, evaluated after `acl`
+    pub scopes: Option<ScopeGrammar>,
+    /// permissions in the token, which narrow the others
+    pub token_acl: Option<TokenAcl>,
+    /// role names to the conditions on the claims of the members
+    #[serde(default)]
+    pub roles: HashMap<String, Subject>,
+    /// layouts of the Dadget topics, the first one which matches is used
+    #[serde(default = "default_dadget_topics")]
+    pub dadget_topics: Vec<DadgetTopic>,
     pub acl: Vec<Acl>,
 }
 
+/// Claim such as `"mqtt_acl": [{"topic": "/telemetry/#", "ops": ["READ", "SUBSCRIBE"]}]`,
+/// where `topic` is an MQTT topic filter and `ops` are operations as in `Accesses`.
 #[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct TokenAcl {
+    pub claim: String,
+    /// denies a token without the claim
+    #[serde(default)]
+    pub required: bool,
+}
+
+/// Grammar of the scopes which grant MQTT operations,
+/// e.g. `mqtt:read:/telemetry` is `<prefix><separator><operation><separator><topic filter>`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields, default, rename_all = "camelCase")]
+pub struct ScopeGrammar {
+    /// space separated string or array
+    pub claim: String,
+    pub prefix: String,
+    pub separator: String,
+    /// operation in the scope to `READ`, `WRITE`, `SUBSCRIBE` or `*`.
+    /// The operation is used as it is when this is empty.
+    pub operations: HashMap<String, String>,
+}
+
+impl Default for ScopeGrammar {
+    fn default() -> Self {
+        ScopeGrammar {
+            claim: "scope".to_string(),
+            prefix: "mqtt".to_string(),
+            separator: ":".to_string(),
+            operations: HashMap::new(),
+        }
+    }
+}
+
+/// File of the attributes of the subjects, which are merged into the claims.
+/// A JSON file maps a subject to the attributes, such as `{"xxxx@example.jp": {"groups": ["a"]}}`.
+/// A CSV file has a header, and the subject in the first column.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct Directory {
+    pub path: String,
+    /// CSV columns which are lists
+    #[serde(default = "default_list_columns")]
+    pub list_columns: Vec<String>,
+    #[serde(default = "default_list_separator")]
+    pub list_separator: String,
+    #[serde(skip)]
+    pub entries: HashMap<String, serde_json::Map<String, Value>>,
+}
+
+fn default_list_columns() -> Vec<String> {
+    vec!["groups".to_string()]
+}
+
+fn default_list_separator() -> String {
+    ";".to_string()
+}
+
+impl Directory {
+    fn load(&mut self) -> Result<(), Box<dyn Error>> {
+        if !self.path.ends_with(".csv") {
+            self.entries = serde_json::from_reader(File::open(&self.path)?)?;
+            return Ok(());
+        }
+        let mut reader = csv::Reader::from_path(&self.path)?;
+        let headers = reader.headers()?.clone();
+        for record in reader.records() {
+            let record = record?;
+            let mut attributes = serde_json::Map::new();
+            for (name, value) in headers.iter().zip(record.iter()).skip(1) {
+                let value = if self.list_columns.iter().any(|x| x == name) {
+                    Value::from(
+                        value
+                            .split(self.list_separator.as_str())
+                            .filter(|x| !x.is_empty())
+                            .collect::<Vec<_>>(),
+                    )
+                } else {
+                    Value::from(value)
+                };
+                attributes.insert(name.to_string(), value);
+            }
+            if let Some(sub) = record.get(0) {
+                self.entries.insert(sub.to_string(), attributes);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Rule which the MQTT client id has to satisfy.
+#[derive(Debug)]
+pub enum ClientIdBinding {
+    /// equal to the claim
+    Claim(String),
+    Regex(Regex),
+    /// regex made from a template such as `${sub}-*`, where `*` matches any string
+    Template(Template),
+}
+
+impl<'de> Deserialize<'de> for ClientIdBinding {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = Value::deserialize(deserializer)?;
+        if s["claim"].is_string() {
+            Ok(ClientIdBinding::Claim(
+                s["claim"].as_str().unwrap().to_string(),
+            ))
+        } else if s["regex"].is_string() {
+            let regex = Regex::new(s["regex"].as_str().unwrap()).map_err(de::Error::custom)?;
+            Ok(ClientIdBinding::Regex(regex))
+        } else if s["template"].is_string() {
+            let template =
+                Template::parse(s["template"].as_str().unwrap()).map_err(de::Error::custom)?;
+            let mut parts = vec![TemplatePart::Text("^".to_string())];
+            for part in template.0 {
+                parts.push(match part {
+                    TemplatePart::Text(x) => TemplatePart::Text(
+                        x.split('*')
+                            .map(regex::escape)
+                            .collect::<Vec<_>>()
+                            .join(".*"),
+                    ),
+                    x => x,
+                });
+            }
+            parts.push(TemplatePart::Text("$".to_string()));
+            Ok(ClientIdBinding::Template(Template(parts)))
+        } else {
+            Err(de::Error::custom("illegal client id binding"))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplatePart {
+    Text(String),
+    /// `${name}`, which is `clientid`, `username`, `claims.<claim>` or `<claim>`
+    Var(String),
+}
+
+/// String with placeholders filled in from the session at check time.
+#[derive(Debug, Clone)]
+pub struct Template(pub Vec<TemplatePart>);
+
+impl Template {
+    pub fn parse(s: &str) -> Result<Template, String> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find("${") {
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed placeholder: {}", s))?
+                + start;
+            if start > 0 {
+                parts.push(TemplatePart::Text(rest[..start].to_string()));
+            }
+            parts.push(TemplatePart::Var(rest[start + 2..end].to_string()));
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Text(rest.to_string()));
+        }
+        Ok(Template(parts))
+    }
+
+    pub fn has_placeholder(&self) -> bool {
+        self.0.iter().any(|x| matches!(x, TemplatePart::Var(_)))
+    }
+
+    /// Tells whether the template has the placeholder `${name}`.
+    pub fn uses(&self, name: &str) -> bool {
+        self.0
+            .iter()
+            .any(|x| matches!(x, TemplatePart::Var(x) if x == name))
+    }
+
+    /// Fills in the placeholders with a dummy value, to validate the template at load time.
+    pub fn sample(&self) -> String {
+        self.0
+            .iter()
+            .map(|x| match x {
+                TemplatePart::Text(x) => x.as_str(),
+                TemplatePart::Var(_) => "x",
+            })
+            .collect()
+    }
+}
+
+/// Where the JWT is read from. `both` prefers the password.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenSource {
+    #[default]
+    Username,
+    Password,
+    Both,
+}
+
+/// Revoked tokens, loaded from the file given by `revocation`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, default, rename_all = "camelCase")]
+pub struct Revocation {
+    pub jti: HashSet<String>,
+    pub sub: HashSet<String>,
+    /// revokes the tokens of a subject issued before the time (unix time)
+    pub issued_before: HashMap<String, i64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, default, rename_all = "camelCase")]
+pub struct SessionPolicy {
+    pub on_expiry: ExpiryAction,
+    /// time a session may outlive its token, in seconds
+    pub grace_period: u64,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExpiryAction {
+    #[default]
+    Deny,
+    Disconnect,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields, default, rename_all = "camelCase")]
+pub struct ClaimValidation {
+    #[serde(deserialize_with = "string_or_list")]
+    pub iss: Option<Vec<String>>,
+    #[serde(deserialize_with = "string_or_list")]
+    pub aud: Option<Vec<String>>,
+    pub nbf: bool,
+    /// clock skew allowed for `exp` and `nbf`, in seconds
+    pub leeway: u64,
+    /// maximum of `exp - iat`, in seconds
+    pub max_lifetime: Option<u64>,
+}
+
+impl Default for ClaimValidation {
+    fn default() -> Self {
+        ClaimValidation {
+            iss: None,
+            aud: None,
+            nbf: true,
+            leeway: 0,
+            max_lifetime: None,
+        }
+    }
+}
+
+fn string_or_list<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Helper {
+        Str(String),
+        List(Vec<String>),
+    }
+    Ok(match Helper::deserialize(deserializer)? {
+        Helper::Str(x) => Some(vec![x]),
+        Helper::List(x) => Some(x),
+    })
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Jwks {
+    Inline(JwkSet),
+    File(String),
+}
+
+/// Operations which `Accesses::operation` matches
+const OPERATIONS: [&str; 4] = ["READ", "WRITE", "SUBSCRIBE", "*"];
+
+impl Config {
+    /// Returns the problems which do not prevent the config from loading,
+    /// such as rules which never apply.
+    pub fn warnings(&self) -> Vec<ConfigError> {
+        let mut warnings = Vec::new();
+        let mut names = HashSet::new();
+        for (i, acl) in self.acl.iter().enumerate() {
+            let warning = |path: &str, reason: String| ConfigError {
+                acl: Some(acl.name.clone()),
+                path: format!("acl[{}]{}", i, path),
+                reason,
+            };
+            if !names.insert(acl.name.as_str()) {
+                warnings.push(warning("", "duplicate acl name".to_string()));
+            }
+            if acl.accesses.is_empty() {
+                warnings.push(warning(".accesses", "no accesses".to_string()));
+            }
+            for x in acl.resource.regexes() {
+                if never_matches(&x) {
+                    warnings.push(warning(
+                        ".resource.path",
+                        format!("regex never matches: {}", x),
+                    ));
+                }
+            }
+            if let Some(reason) = self.unbound_placeholder(acl.resource.templates()) {
+                warnings.push(warning(".resource.path", reason));
+            }
+            for (j, access) in acl.accesses.iter().enumerate() {
+                let path = format!(".accesses[{}]", j);
+                if !OPERATIONS
+                    .iter()
+                    .any(|x| x.eq_ignore_ascii_case(&access.operation))
+                {
+                    warnings.push(warning(
+                        &format!("{}.operation", path),
+                        format!("unknown operation: {}", access.operation),
+                    ));
+                }
+                for role in &access.roles {
+                    if !self.roles.contains_key(role) {
+                        warnings.push(warning(
+                            &format!("{}.roles", path),
+                            format!("undefined role: {}", role),
+                        ));
+                    }
+                }
+                let mut regexes = Vec::new();
+                if let Some(ref x) = access.subject {
+                    x.regexes(&mut regexes);
+                }
+                for x in regexes.iter().filter(|x| never_matches(x)) {
+                    warnings.push(warning(
+                        &format!("{}.subject", path),
+                        format!("regex never matches: {}", x),
+                    ));
+                }
+                let mut templates = Vec::new();
+                if let Some(ref x) = access.subject {
+                    x.templates(&mut templates);
+                }
+                if let Some(reason) = self.unbound_placeholder(templates) {
+                    warnings.push(warning(&format!("{}.subject", path), reason));
+                }
+                if access.transaction.is_some() {
+                    if let Resource::Mqtt(_) = acl.resource {
+                        warnings.push(warning(
+                            &format!("{}.transaction", path),
+                            "transaction rule on an mqtt resource".to_string(),
+                        ));
+                    }
+                }
+            }
+        }
+        for (name, x) in &self.roles {
+            let mut regexes = Vec::new();
+            x.regexes(&mut regexes);
+            for x in regexes.iter().filter(|x| never_matches(x)) {
+                warnings.push(ConfigError {
+                    acl: None,
+                    path: format!("roles.{}", name),
+                    reason: format!("regex never matches: {}", x),
+                });
+            }
+            let mut templates = Vec::new();
+            x.templates(&mut templates);
+            if let Some(reason) = self.unbound_placeholder(templates) {
+                warnings.push(ConfigError {
+                    acl: None,
+                    path: format!("roles.{}", name),
+                    reason,
+                });
+            }
+        }
+        warnings
+    }
+
+    /// Returns why a placeholder in the templates is not bound to the token,
+    /// so that a client can pick its value.
+    fn unbound_placeholder(&self, templates: Vec<&Template>) -> Option<String> {
+        if self.client_id_binding.is_none() && templates.iter().any(|x| x.uses("clientid")) {
+            return Some("${clientid} without clientIdBinding".to_string());
+        }
+        if self.username_claim.is_none() && templates.iter().any(|x| x.uses("username")) {
+            return Some("${username} without usernameClaim".to_string());
+        }
+        None
+    }
+
+    /// Returns the files which are watched along with the config file.
+    pub fn linked_files(&self) -> Vec<&str> {
+        let mut list = Vec::new();
+        if let Some(Jwks::File(ref x)) = self.jwks {
+            list.push(x.as_str());
+        }
+        if let Some(ref x) = self.revocation {
+            list.push(x.as_str());
+        }
+        if let Some(ref x) = self.directory {
+            list.push(x.path.as_str());
+        }
+        list
+    }
+
+    /// Returns the algorithms a token may be signed with.
+    /// Without an explicit allowlist, the algorithms of the configured keys are accepted.
+    pub fn allowed_algorithms(&self) -> Vec<Algorithm> {
+        match self.algorithms {
+            Some(ref x) => x.clone(),
+            None => {
+                let mut list = Vec::new();
+                for key in &self.keys {
+                    if !list.contains(&key.alg) {
+                        list.push(key.alg);
+                    }
+                }
+                list
+            }
+        }
+    }
+}
+
+pub struct VerificationKey {
+    pub kid: Option<String>,
+    pub alg: Algorithm,
+    pub kty: String,
+    pub key: DecodingKey,
+}
+
+impl fmt::Debug for VerificationKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("VerificationKey")
+            .field("kid", &self.kid)
+            .field("alg", &self.alg)
+            .field("kty", &self.kty)
+            .finish()
+    }
+}
+
+impl VerificationKey {
+    pub fn from_secret(secret: &str) -> VerificationKey {
+        VerificationKey {
+            kid: None,
+            alg: Algorithm::HS256,
+            kty: "oct".to_string(),
+            key: DecodingKey::from_secret(secret.as_bytes()),
+        }
+    }
+
+    /// Converts a JWK. When the JWK has no `alg`, it is derived from the key type.
+    pub fn from_jwk(jwk: &Jwk) -> Result<VerificationKey, Box<dyn Error>> {
+        if let Some(PublicKeyUse::Encryption) = jwk.common.public_key_use {
+            return Err(From::from("key is not for signature"));
+        }
+        let alg = match jwk.common.key_algorithm {
+            Some(ref x) => Algorithm::from_str(&format!("{:?}", x))?,
+            None => match jwk.algorithm {
+                AlgorithmParameters::RSA(_) => Algorithm::RS256,
+                AlgorithmParameters::EllipticCurve(ref x) => match x.curve {
+                    EllipticCurve::P256 => Algorithm::ES256,
+                    EllipticCurve::P384 => Algorithm::ES384,
+                    _ => return Err(From::from("unsupported curve")),
+                },
+                AlgorithmParameters::OctetKeyPair(_) => Algorithm::EdDSA,
+                AlgorithmParameters::OctetKey(_) => Algorithm::HS256,
+            },
+        };
+        Ok(VerificationKey {
+            kid: jwk.common.key_id.clone(),
+            alg,
+            kty: key_type(alg).to_string(),
+            key: DecodingKey::from_jwk(jwk)?,
+        })
+    }
+}
+
+fn key_type(alg: Algorithm) -> &'static str {
+    match alg {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => "oct",
+        Algorithm::RS256
+        | Algorithm::RS384
+        | Algorithm::RS512
+        | Algorithm::PS256
+        | Algorithm::PS384
+        | Algorithm::PS512 => "RSA",
+        Algorithm::ES256 | Algorithm::ES384 => "EC",
+        Algorithm::EdDSA => "OKP",
+    }
+}
+
+impl<'de> Deserialize<'de> for VerificationKey {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Helper {
+            kid: Option<String>,
+            alg: Algorithm,
+            kty: Option<String>,
+            secret: Option<String>,
+            pem: Option<String>,
+            der: Option<String>,
+        }
+        let helper = Helper::deserialize(deserializer)?;
+        let kty = key_type(helper.alg);
+        if let Some(ref x) = helper.kty {
+            if x != kty {
+                return Err(de::Error::custom(format!(
+                    "key type {} does not match algorithm {:?}",
+                    x, helper.alg
+                )));
+            }
+        }
+        let pem = match (helper.pem, helper.der) {
+            (Some(pem), None) => Some(pem),
+            (None, Some(der)) => {
+                let body: String = der.split_whitespace().collect();
+                Some(format!(
+                    "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+                    body
+                ))
+            }
+            (None, None) => None,
+            _ => return Err(de::Error::custom("specify either pem or der")),
+        };
+        let key = match (kty, helper.secret, pem) {
+            ("oct", Some(secret), None) => Ok(DecodingKey::from_secret(secret.as_bytes())),
+            ("RSA", None, Some(pem)) => DecodingKey::from_rsa_pem(pem.as_bytes()),
+            ("EC", None, Some(pem)) => DecodingKey::from_ec_pem(pem.as_bytes()),
+            ("OKP", None, Some(pem)) => DecodingKey::from_ed_pem(pem.as_bytes()),
+            ("oct", _, _) => return Err(de::Error::custom("oct key requires a secret")),
+            _ => return Err(de::Error::custom("public key requires pem or der")),
+        }
+        .map_err(de::Error::custom)?;
+        Ok(VerificationKey {
+            kid: helper.kid,
+            alg: helper.alg,
+            kty: kty.to_string(),
+            key,
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct Acl {
     pub name: String,
     pub resource: Resource,
     pub accesses: Vec<Accesses>,
 }
 
+/// `{"type": "dadget" or "mqtt", "path": ...}`
 #[derive(Debug)]
 pub enum Resource {
     Dadget(DadgetResource),
     Mqtt(MqttResource),
-    Other,
 }
 
-#[derive(Deserialize, Debug)]
-pub struct DadgetResource {
-    pub path: DadgetResourcePath,
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum ResourceType {
+    Dadget,
+    Mqtt,
 }
 
-#[derive(Deserialize, Debug)]
-pub struct MqttResource {
-    pub path: MqttResourcePath,
+impl ResourceType {
+    fn path<'de, D>(self, deserializer: D) -> Result<Resource, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(match self {
+            ResourceType::Dadget => Resource::Dadget(DadgetResource {
+                path: DadgetResourcePath::deserialize(deserializer)?,
+            }),
+            ResourceType::Mqtt => Resource::Mqtt(MqttResource {
+                path: MqttResourcePath::deserialize(deserializer)?,
+            }),
+        })
+    }
 }
 
 impl<'de> Deserialize<'de> for Resource {
@@ -41,36 +644,357 @@ impl<'de> Deserialize<'de> for Resource {
     where
         D: Deserializer<'de>,
     {
-        let helper: Value = Deserialize::deserialize(deserializer)?;
+        deserializer.deserialize_map(ResourceVisitor)
+    }
+}
 
-        use self::Resource::*;
-        use serde_json::from_value;
+/// Reads the fields one by one rather than buffering them as `#[serde(tag)]` does,
+/// so that an error in the path is located at `resource.path`.
+struct ResourceVisitor;
 
-        let resource = match helper.get("type") {
-            Some(x) if x.as_str() == Some("dadget") => Dadget(from_value(helper).unwrap()),
-            Some(x) if x.as_str() == Some("mqtt") => Mqtt(from_value(helper).unwrap()),
-            _ => Other,
-        };
-        Ok(resource)
+impl<'de> Visitor<'de> for ResourceVisitor {
+    type Value = Resource;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a resource")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Resource, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut kind: Option<ResourceType> = None;
+        let mut resource = None;
+        // a path before the type is read once the type is known,
+        // and an error in it is located at the resource
+        let mut pending: Option<Value> = None;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "type" if kind.is_some() => return Err(de::Error::duplicate_field("type")),
+                "type" => kind = Some(map.next_value()?),
+                "path" if resource.is_some() || pending.is_some() => {
+                    return Err(de::Error::duplicate_field("path"))
+                }
+                "path" => match kind {
+                    Some(x) => resource = Some(map.next_value_seed(PathSeed(x))?),
+                    None => pending = Some(map.next_value()?),
+                },
+                x => return Err(de::Error::unknown_field(x, &["type", "path"])),
+            }
+        }
+        let kind = kind.ok_or_else(|| de::Error::missing_field("type"))?;
+        match (resource, pending) {
+            (Some(x), _) => Ok(x),
+            (None, Some(x)) => kind
+                .path(x)
+                .map_err(|e| de::Error::custom(format!("path: {}", e))),
+            (None, None) => Err(de::Error::missing_field("path")),
+        }
+    }
+}
+
+struct PathSeed(ResourceType);
+
+impl<'de> de::DeserializeSeed<'de> for PathSeed {
+    type Value = Resource;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Resource, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        self.0.path(deserializer)
     }
 }
 
+impl Resource {
+    /// Returns the regexes of the path, with dummy values in the placeholders.
+    fn regexes(&self) -> Vec<String> {
+        match self {
+            Resource::Dadget(x) => x
+                .path
+                .0
+                .iter()
+                .filter_map(|(_, x)| match x {
+                    DadgetSegment::Regex(x) => Some(x.as_str().to_string()),
+                    DadgetSegment::RegexTemplate(x) => Some(x.sample()),
+                    _ => None,
+                })
+                .collect(),
+            Resource::Mqtt(x) => match x.path {
+                MqttResourcePath::Regex(ref x) => vec![x.as_str().to_string()],
+                MqttResourcePath::RegexTemplate(ref x) => vec![x.sample()],
+                _ => vec![],
+            },
+        }
+    }
+
+    /// Returns the templates of the path.
+    fn templates(&self) -> Vec<&Template> {
+        match self {
+            Resource::Dadget(x) => x
+                .path
+                .0
+                .iter()
+                .filter_map(|(_, x)| match x {
+                    DadgetSegment::StrTemplate(x) | DadgetSegment::RegexTemplate(x) => Some(x),
+                    _ => None,
+                })
+                .collect(),
+            Resource::Mqtt(x) => match x.path {
+                MqttResourcePath::StrTemplate(ref x) | MqttResourcePath::RegexTemplate(ref x) => {
+                    vec![x]
+                }
+                _ => vec![],
+            },
+        }
+    }
+}
+
+/// Tells whether a regex matches no text at all, such as `[^\s\S]` or `a$b`.
+fn never_matches(pattern: &str) -> bool {
+    match regex_syntax::parse(pattern) {
+        Ok(hir) => is_empty_language(&hir),
+        Err(_) => false,
+    }
+}
+
+fn is_empty_language(hir: &Hir) -> bool {
+    let is_nonempty = |x: &Hir| x.properties().minimum_len() != Some(0);
+    match hir.kind() {
+        _ if hir.properties().minimum_len().is_none() => true,
+        HirKind::Capture(x) => is_empty_language(&x.sub),
+        HirKind::Repetition(x) => x.min > 0 && is_empty_language(&x.sub),
+        HirKind::Alternation(x) => x.iter().all(is_empty_language),
+        // text before the start or after the end
+        HirKind::Concat(x) => x.iter().enumerate().any(|(i, y)| match y.kind() {
+            HirKind::Look(Look::Start) => x[..i].iter().any(is_nonempty),
+            HirKind::Look(Look::End) => x[i + 1..].iter().any(is_nonempty),
+            _ => is_empty_language(y),
+        }),
+        _ => false,
+    }
+}
+
+#[derive(Debug)]
+pub struct DadgetResource {
+    pub path: DadgetResourcePath,
+}
+
+#[derive(Debug)]
+pub struct MqttResource {
+    pub path: MqttResourcePath,
+}
+
 #[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct Accesses {
+    /// `READ`, `WRITE`, `SUBSCRIBE` or `*`
     pub operation: String,
-    pub subject: Option<HashMap<String, SubjectRegex>>,
+    pub subject: Option<Subject>,
+    /// applies to the members of any of the roles
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub effect: Effect,
+    /// largest payload in bytes on publish
+    pub max_payload: Option<u64>,
+    /// QoS levels allowed on publish
+    pub qos: Option<Vec<i32>>,
+    /// `false` forbids retained messages on publish
+    pub retain: Option<bool>,
+    /// rule on the transactions written to a Dadget resource
+    pub transaction: Option<TransactionRule>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct TransactionRule {
+    /// allowed types such as `insert`, `update`, `delete` and `truncate`
+    pub types: Option<Vec<String>>,
+    /// condition on the fields of the documents before and after the transaction.
+    /// An update in `operator` is checked on `before` with its `$set` and `$unset` applied,
+    /// and the other operators are denied.
+    pub document: Option<Subject>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Effect {
+    #[default]
+    Allow,
+    Deny,
 }
 
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Combining {
+    /// the first applicable access decides
+    #[default]
+    FirstApplicable,
+    /// any applicable deny wins
+    DenyOverrides,
+    /// any applicable allow wins
+    PermitOverrides,
+}
+
+/// Error or warning on a config file, located by a JSON path such as `acl[2].resource.path`.
+#[derive(Debug)]
+pub struct ConfigError {
+    /// name of the ACL which the path is in
+    pub acl: Option<String>,
+    pub path: String,
+    pub reason: String,
+}
+
+impl ConfigError {
+    /// Names the ACL from the config as it is, since the path has no name.
+    fn locate(root: &Value, path: String, reason: String) -> ConfigError {
+        let acl = path
+            .strip_prefix("acl[")
+            .and_then(|x| x.split(']').next())
+            .and_then(|x| x.parse::<usize>().ok())
+            .and_then(|i| root["acl"][i]["name"].as_str())
+            .map(String::from);
+        ConfigError { acl, path, reason }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.acl {
+            Some(ref acl) => write!(f, "{} (acl {}): {}", self.path, acl, self.reason),
+            None => write!(f, "{}: {}", self.path, self.reason),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
 pub fn read_from_file<P: AsRef<Path>>(path: P) -> Result<Config, Box<dyn Error>> {
+    let mut u = parse_file(path)?;
+    u.load_linked_files()?;
+    Ok(u)
+}
+
+/// Reads the config file alone, with the paths of the linked files resolved.
+pub fn parse_file<P: AsRef<Path>>(path: P) -> Result<Config, Box<dyn Error>> {
+    // read from the text rather than a `Value`, whose keys are sorted,
+    // so that the type of a resource comes before its path as in the file
+    let text = fs::read_to_string(&path)?;
+    let mut deserializer = serde_json::Deserializer::from_str(&text);
+    let mut u: Config = serde_path_to_error::deserialize(&mut deserializer).map_err(|e| {
+        let root = serde_json::from_str(&text).unwrap_or_default();
+        ConfigError::locate(&root, e.path().to_string(), e.inner().to_string())
+    })?;
+    deserializer.end()?;
+    if let Some(ref secret) = u.key {
+        u.keys.push(VerificationKey::from_secret(secret));
+    }
+    // a relative path is relative to the config file
+    if let Some(dir) = path.as_ref().parent() {
+        if let Some(Jwks::File(ref mut jwks_path)) = u.jwks {
+            *jwks_path = dir.join(&jwks_path).to_string_lossy().to_string();
+        }
+        if let Some(ref mut revocation_path) = u.revocation {
+            *revocation_path = dir.join(&revocation_path).to_string_lossy().to_string();
+        }
+        if let Some(ref mut directory) = u.directory {
+            directory.path = dir.join(&directory.path).to_string_lossy().to_string();
+        }
+    }
+    Ok(u)
+}
+
+impl Config {
+    /// Reads the JWKS, the revocation list and the directory which the config names.
+    pub fn load_linked_files(&mut self) -> Result<(), Box<dyn Error>> {
+        let jwk_set = match self.jwks {
+            Some(Jwks::Inline(ref x)) => Some(x.clone()),
+            Some(Jwks::File(ref x)) => Some(read_jwks_from_file(x)?),
+            None => None,
+        };
+        if let Some(jwk_set) = jwk_set {
+            for jwk in &jwk_set.keys {
+                match VerificationKey::from_jwk(jwk) {
+                    Ok(x) => self.keys.push(x),
+                    Err(e) => warn!("skip jwk {:?}: {}", jwk.common.key_id, e),
+                }
+            }
+        }
+        if let Some(ref x) = self.revocation {
+            self.revoked = serde_json::from_reader(File::open(x)?)?;
+        }
+        if let Some(ref mut x) = self.directory {
+            x.load()?;
+        }
+        Ok(())
+    }
+}
+
+fn read_jwks_from_file<P: AsRef<Path>>(path: P) -> Result<JwkSet, Box<dyn Error>> {
     let file = File::open(path)?;
     let u = serde_json::from_reader(file)?;
     Ok(u)
 }
 
-#[derive(Debug)]
-pub enum DadgetResourcePath {
-    Str(Vec<String>),
-    Regex(Vec<Regex>),
+/// Conditions on the named segments of a Dadget topic.
+/// A string or `{"regex": ...}` path such as `db/subset` is positional,
+/// and its levels are the segments `database` and `subset`.
+#[derive(Debug, Clone)]
+pub struct DadgetResourcePath(pub Vec<(String, DadgetSegment)>);
+
+/// Names of the segments of a positional path
+pub const POSITIONAL_SEGMENTS: [&str; 2] = ["database", "subset"];
+
+#[derive(Debug, Clone)]
+pub enum DadgetSegment {
+    Str(String),
+    Regex(Regex),
+    /// value with placeholders, which becomes `Str` at check time
+    StrTemplate(Template),
+    /// regex with placeholders, which becomes `Regex` at check time
+    RegexTemplate(Template),
+}
+
+impl DadgetSegment {
+    fn from_value(x: &str) -> Result<DadgetSegment, String> {
+        let template = Template::parse(x)?;
+        if template.has_placeholder() {
+            return Ok(DadgetSegment::StrTemplate(template));
+        }
+        Ok(DadgetSegment::Str(template.sample()))
+    }
+
+    /// The regex has to match the whole segment.
+    fn from_regex(x: &str) -> Result<DadgetSegment, String> {
+        let template = Template::parse(&format!("^{}$", x))?;
+        let regex = Regex::new(&template.sample()).map_err(|e| e.to_string())?;
+        if template.has_placeholder() {
+            return Ok(DadgetSegment::RegexTemplate(template));
+        }
+        Ok(DadgetSegment::Regex(regex))
+    }
+}
+
+impl DadgetResourcePath {
+    fn positional<F>(path: &str, parse: F) -> Result<DadgetResourcePath, String>
+    where
+        F: Fn(&str) -> Result<DadgetSegment, String>,
+    {
+        let levels: Vec<_> = path.split('/').filter(|x| !x.is_empty()).collect();
+        if levels.len() > POSITIONAL_SEGMENTS.len() {
+            return Err(format!(
+                "too many levels in positional path, use segments: {}",
+                path
+            ));
+        }
+        let segments = POSITIONAL_SEGMENTS
+            .iter()
+            .zip(levels)
+            .map(|(name, x)| Ok((name.to_string(), parse(x)?)))
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(DadgetResourcePath(segments))
+    }
 }
 
 impl<'de> Deserialize<'de> for DadgetResourcePath {
@@ -79,39 +1003,109 @@ impl<'de> Deserialize<'de> for DadgetResourcePath {
         D: Deserializer<'de>,
     {
         let s = Value::deserialize(deserializer)?;
-        if s.is_string() {
-            let path: Vec<_> = s
-                .as_str()
-                .unwrap()
-                .split('/')
-                .filter(|x| x.len() > 0)
-                .map(|x| x.to_string())
-                .collect();
-            Ok(DadgetResourcePath::Str(path))
-        } else if s.is_object() && s["regex"].is_string() {
-            let path: Vec<_> = s["regex"]
-                .as_str()
-                .unwrap()
-                .split('/')
-                .filter(|&x| x.len() > 0)
-                .try_fold(Vec::new(), |mut vec, x| {
-                    regex::Regex::new(&format!("^{}$", x)).map(|x| {
-                        vec.push(x);
-                        vec
-                    })
+        if s.as_object().is_some_and(|x| x.len() != 1) {
+            return Err(de::Error::custom("specify either regex or segments"));
+        }
+        if let Some(path) = s.as_str() {
+            DadgetResourcePath::positional(path, DadgetSegment::from_value)
+                .map_err(de::Error::custom)
+        } else if let Some(path) = s["regex"].as_str() {
+            DadgetResourcePath::positional(path, DadgetSegment::from_regex)
+                .map_err(de::Error::custom)
+        } else if let Some(segments) = s["segments"].as_object() {
+            let segments = segments
+                .iter()
+                .map(|(name, x)| {
+                    let segment = if let Some(x) = x.as_str() {
+                        DadgetSegment::from_value(x)
+                    } else if x.as_object().is_some_and(|x| x.len() != 1) {
+                        Err(format!("illegal segment: {}", name))
+                    } else if let Some(x) = x["regex"].as_str() {
+                        DadgetSegment::from_regex(x)
+                    } else {
+                        Err(format!("illegal segment: {}", name))
+                    };
+                    Ok((name.clone(), segment?))
                 })
-                .map_err(|x| de::Error::custom(x))?;
-            Ok(DadgetResourcePath::Regex(path))
+                .collect::<Result<Vec<_>, String>>()
+                .map_err(de::Error::custom)?;
+            Ok(DadgetResourcePath(segments))
         } else {
             Err(de::Error::custom("illegal resource path"))
         }
     }
 }
 
+/// Layout of a Dadget topic such as `/m/d/{database}/subset/{subset}/transaction`,
+/// where a level `{name}` is a segment, and the other levels are literal.
+#[derive(Debug)]
+pub struct DadgetTopic(pub Vec<DadgetTopicLevel>);
+
 #[derive(Debug)]
+pub enum DadgetTopicLevel {
+    Literal(String),
+    Segment(String),
+}
+
+impl DadgetTopic {
+    pub fn parse(s: &str) -> Result<DadgetTopic, String> {
+        if topic::has_wildcard(s) {
+            return Err(format!("wildcard in dadget topic: {}", s));
+        }
+        let mut names = HashSet::new();
+        let mut levels = Vec::new();
+        for x in s.split('/') {
+            if x.starts_with('{') && x.ends_with('}') && x.len() > 2 {
+                let name = &x[1..x.len() - 1];
+                if !names.insert(name) {
+                    return Err(format!("duplicate segment {} in dadget topic: {}", name, s));
+                }
+                levels.push(DadgetTopicLevel::Segment(name.to_string()));
+            } else {
+                levels.push(DadgetTopicLevel::Literal(x.to_string()));
+            }
+        }
+        Ok(DadgetTopic(levels))
+    }
+}
+
+impl<'de> Deserialize<'de> for DadgetTopic {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        DadgetTopic::parse(&s).map_err(de::Error::custom)
+    }
+}
+
+fn default_dadget_topics() -> Vec<DadgetTopic> {
+    DEFAULT_DADGET_TOPICS
+        .iter()
+        .map(|x| DadgetTopic::parse(x).unwrap())
+        .collect()
+}
+
+#[derive(Debug, Clone)]
 pub enum MqttResourcePath {
     Str(String),
+    /// path with `+` or `#`, which is matched level by level
+    Filter(String),
     Regex(Regex),
+    /// path with placeholders, which becomes `Str` or `Filter` at check time
+    StrTemplate(Template),
+    /// regex with placeholders, which becomes `Regex` at check time
+    RegexTemplate(Template),
+}
+
+impl MqttResourcePath {
+    pub fn from_path(x: &str) -> MqttResourcePath {
+        if topic::has_wildcard(x) {
+            MqttResourcePath::Filter(String::from(x))
+        } else {
+            MqttResourcePath::Str(String::from(x))
+        }
+    }
 }
 
 impl<'de> Deserialize<'de> for MqttResourcePath {
@@ -121,10 +1115,21 @@ impl<'de> Deserialize<'de> for MqttResourcePath {
     {
         let s = Value::deserialize(deserializer)?;
         if s.is_string() {
-            Ok(MqttResourcePath::Str(String::from(s.as_str().unwrap())))
-        } else if s.is_object() && s["regex"].is_string() {
+            let x = s.as_str().unwrap();
+            let template = Template::parse(x).map_err(de::Error::custom)?;
+            topic::validate_filter(&template.sample()).map_err(de::Error::custom)?;
+            if template.has_placeholder() {
+                Ok(MqttResourcePath::StrTemplate(template))
+            } else {
+                Ok(MqttResourcePath::from_path(x))
+            }
+        } else if s.as_object().is_some_and(|x| x.len() == 1) && s["regex"].is_string() {
             let x = s["regex"].as_str().unwrap();
-            match regex::Regex::new(&format!("^{}", x)) {
+            let template = Template::parse(&format!("^{}", x)).map_err(de::Error::custom)?;
+            match regex::Regex::new(&template.sample()) {
+                Ok(_) if template.has_placeholder() => {
+                    Ok(MqttResourcePath::RegexTemplate(template))
+                }
                 Ok(x) => Ok(MqttResourcePath::Regex(x)),
                 Err(x) => Err(de::Error::custom(x)),
             }
@@ -135,15 +1140,173 @@ impl<'de> Deserialize<'de> for MqttResourcePath {
 }
 
 #[derive(Debug)]
-pub struct SubjectRegex(pub Regex);
+pub enum SubjectRegex {
+    Regex(Regex),
+    /// regex with placeholders such as `^${path.tenant}$`
+    Template(Template),
+}
+
+/// Condition tree on the claims
+#[derive(Debug)]
+pub enum Subject {
+    /// conditions keyed by a claim name, a dotted path or a JSON pointer, all of which have to hold.
+    /// A claim named `allOf`, `anyOf` or `not` is written as a JSON pointer.
+    Claims(HashMap<String, ClaimCondition>),
+    AllOf(Vec<Subject>),
+    AnyOf(Vec<Subject>),
+    Not(Box<Subject>),
+}
 
-impl<'de> Deserialize<'de> for SubjectRegex {
+impl<'de> Deserialize<'de> for Subject {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'de>,
     {
-        let s = String::deserialize(deserializer)?;
-        let regex = Regex::new(&s).map_err(|x| de::Error::custom(x))?;
-        Ok(SubjectRegex(regex))
+        deserializer.deserialize_map(SubjectVisitor)
+    }
+}
+
+/// Reads the entries one by one, so that an error in a nested condition
+/// is located at its own path such as `subject.anyOf[1].email`.
+struct SubjectVisitor;
+
+impl<'de> Visitor<'de> for SubjectVisitor {
+    type Value = Subject;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("conditions on the claims")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Subject, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let is_operator = |x: &str| x == "allOf" || x == "anyOf" || x == "not";
+        let mut claims = HashMap::new();
+        let mut operator = None;
+        while let Some(key) = map.next_key::<String>()? {
+            if operator.is_some() || (!claims.is_empty() && is_operator(&key)) {
+                return Err(de::Error::custom(
+                    "allOf, anyOf or not cannot be combined with other keys",
+                ));
+            }
+            match key.as_str() {
+                "allOf" => operator = Some(Subject::AllOf(map.next_value()?)),
+                "anyOf" => operator = Some(Subject::AnyOf(map.next_value()?)),
+                "not" => operator = Some(Subject::Not(Box::new(map.next_value()?))),
+                _ => {
+                    let condition = map.next_value()?;
+                    claims.insert(key, condition);
+                }
+            }
+        }
+        Ok(operator.unwrap_or(Subject::Claims(claims)))
+    }
+}
+
+impl Subject {
+    /// Collects the regexes of the conditions, with dummy values in the placeholders.
+    fn regexes(&self, out: &mut Vec<String>) {
+        match self {
+            Subject::Claims(x) => {
+                for x in x.values() {
+                    match x {
+                        ClaimCondition::Regex(SubjectRegex::Regex(x)) => {
+                            out.push(x.as_str().to_string())
+                        }
+                        ClaimCondition::Regex(SubjectRegex::Template(x)) => out.push(x.sample()),
+                        _ => {}
+                    }
+                }
+            }
+            Subject::AllOf(x) | Subject::AnyOf(x) => {
+                for x in x {
+                    x.regexes(out);
+                }
+            }
+            Subject::Not(x) => x.regexes(out),
+        }
+    }
+
+    /// Collects the templates of the conditions.
+    fn templates<'a>(&'a self, out: &mut Vec<&'a Template>) {
+        match self {
+            Subject::Claims(x) => {
+                for x in x.values() {
+                    if let ClaimCondition::Regex(SubjectRegex::Template(x)) = x {
+                        out.push(x);
+                    }
+                }
+            }
+            Subject::AllOf(x) | Subject::AnyOf(x) => {
+                for x in x {
+                    x.templates(out);
+                }
+            }
+            Subject::Not(x) => x.templates(out),
+        }
+    }
+}
+
+impl SubjectRegex {
+    pub fn parse(s: &str) -> Result<SubjectRegex, String> {
+        let template = Template::parse(s)?;
+        let regex = Regex::new(&template.sample()).map_err(|e| e.to_string())?;
+        if template.has_placeholder() {
+            Ok(SubjectRegex::Template(template))
+        } else {
+            Ok(SubjectRegex::Regex(regex))
+        }
+    }
+}
+
+/// Condition on a claim. An array claim satisfies it when any element does.
+#[derive(Debug)]
+pub enum ClaimCondition {
+    /// a string, or `{"regex": ...}`
+    Regex(SubjectRegex),
+    /// a number or a boolean, or `{"equals": ...}`
+    Equals(Value),
+    /// `{"min": ..., "max": ...}`, both inclusive
+    Range { min: Option<f64>, max: Option<f64> },
+}
+
+impl<'de> Deserialize<'de> for ClaimCondition {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = Value::deserialize(deserializer)?;
+        match s {
+            Value::String(ref x) => SubjectRegex::parse(x)
+                .map(ClaimCondition::Regex)
+                .map_err(de::Error::custom),
+            Value::Bool(_) | Value::Number(_) => Ok(ClaimCondition::Equals(s)),
+            Value::Object(ref x) if x.len() == 1 && x.contains_key("regex") => {
+                match x["regex"].as_str() {
+                    Some(x) => SubjectRegex::parse(x)
+                        .map(ClaimCondition::Regex)
+                        .map_err(de::Error::custom),
+                    None => Err(de::Error::custom("regex must be a string")),
+                }
+            }
+            Value::Object(ref x) if x.len() == 1 && x.contains_key("equals") => {
+                Ok(ClaimCondition::Equals(x["equals"].clone()))
+            }
+            Value::Object(ref x) if !x.is_empty() && x.keys().all(|x| x == "min" || x == "max") => {
+                let bound = |key: &str| match x.get(key) {
+                    None => Ok(None),
+                    Some(x) => x
+                        .as_f64()
+                        .map(Some)
+                        .ok_or_else(|| de::Error::custom(format!("{} must be a number", key))),
+                };
+                Ok(ClaimCondition::Range {
+                    min: bound("min")?,
+                    max: bound("max")?,
+                })
+            }
+            _ => Err(de::Error::custom("illegal claim condition")),
+        }
     }
 }
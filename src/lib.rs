@@ -2,37 +2,47 @@ extern crate jsonwebtoken;
 #[macro_use]
 extern crate serde_derive;
 extern crate regex;
+extern crate regex_syntax;
 extern crate serde;
+#[macro_use]
 extern crate serde_json;
+extern crate serde_path_to_error;
 #[macro_use]
 extern crate log;
 extern crate chrono;
-extern crate simplelog;
-#[macro_use]
-extern crate lazy_static;
+extern crate csv;
+#[cfg(target_os = "linux")]
+extern crate inotify;
 extern crate percent_encoding;
+extern crate simplelog;
 
 mod config;
 mod misc;
+mod topic;
+mod watch;
 use chrono::prelude::*;
-use jsonwebtoken::{decode, Algorithm, Validation};
+pub use config::ConfigError;
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{decode, decode_header, Algorithm, TokenData, Validation};
 use percent_encoding::percent_decode;
 use regex::Regex;
 use serde_json::Value;
 use simplelog::{
     CombinedLogger, Config, Level, LevelFilter, SharedLogger, TermLogger, WriteLogger,
 };
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::ffi::CStr;
 use std::fmt;
 use std::fs::OpenOptions;
+use std::hash::Hash;
 use std::io::{BufWriter, Write};
 use std::os::raw::{c_char, c_int, c_long, c_uint, c_void};
 use std::str::FromStr;
 use std::sync::mpsc::{channel, Sender};
-use std::sync::{Mutex, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 pub const DEFAULT_CONFIG_PATH_OPT_KEY: &str = "chipin_config_path";
 pub const DEFAULT_CONFIG_PATH: &str = "/etc/mosquitto/acl.json";
@@ -41,12 +51,20 @@ pub const DEFAULT_AUTH_LOG_FILE_NAME: &str = "/var/log/mosquitto/auth.log";
 pub const DEFAULT_LOG_FILE_NAME_OPT_KEY: &str = "chipin_log_file";
 pub const DEFAULT_LOG_FILE_NAME: &str = "/var/log/mosquitto/chipin-plugin.log";
 pub const DEFAULT_LOG_LEVEL_OPT_KEY: &str = "chipin_log_level";
+pub const DEFAULT_CONFIG_POLL_INTERVAL_OPT_KEY: &str = "chipin_config_poll_interval";
+/// File which the version, the hash and the last failure of the config are written to.
+/// Nothing is written without it.
+pub const DEFAULT_CONFIG_STATUS_FILE_OPT_KEY: &str = "chipin_config_status_file";
+/// Interval in seconds to poll the config files, besides the inotify events. `0` disables polling.
 pub const CONFIG_FILE_CHECK_INTERVAL: u64 = 60;
+const REGISTERED_CLAIMS: [&str; 7] = ["iss", "sub", "aud", "exp", "nbf", "iat", "jti"];
 pub const LOG_DATE_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%Z";
-pub const PATH_TRANSACTION: &str = r"^/m/d/([^/]+)/transaction$";
-pub const PATH_SUBSET_TRANSACTION: &str = r"^/m/d/([^/]+)/subset/([^/]+)/transaction$";
+pub const DEFAULT_DADGET_TOPICS: [&str; 2] = [
+    "/m/d/{database}/transaction",
+    "/m/d/{database}/subset/{subset}/transaction",
+];
 
-pub const NULL: *const c_char = 0 as *const c_char;
+pub const NULL: *const c_char = std::ptr::null();
 
 pub const MOSQ_ACL_NONE: c_int = 0x00;
 pub const MOSQ_ACL_READ: c_int = 0x01;
@@ -72,25 +90,179 @@ pub const MOSQ_ERR_ERRNO: c_int = 14;
 pub const MOSQ_ERR_EAI: c_int = 15;
 pub const MOSQ_ERR_PROXY: c_int = 16;
 
-lazy_static! {
-    static ref REGEX_PATH_TRANSACTION: Regex = Regex::new(PATH_TRANSACTION).unwrap();
-    static ref REGEX_PATH_SUBSET_TRANSACTION: Regex = Regex::new(PATH_SUBSET_TRANSACTION).unwrap();
-}
+/// Returned by `proc_mosquitto_auth_acl_check_v3` when the client has to be disconnected.
+pub const CHIPIN_ERR_SESSION_EXPIRED: c_int = 100;
+
+type LogSender = Sender<(DateTime<Local>, String)>;
+
+/// Named groups captured by a regex resource path
+type Captures = HashMap<String, String>;
+
+/// Percent-decoded segments of a Dadget topic by name
+type Segments = HashMap<String, String>;
 
 pub struct UserData {
     config_path: String,
-    config_info: RwLock<ConfigInfo>,
-    client_map: RwLock<HashMap<*const mosquitto, String>>,
-    log: Mutex<Option<Sender<(DateTime<Local>, String)>>>,
+    config_info: Arc<RwLock<ConfigInfo>>,
+    client_map: RwLock<HashMap<*const mosquitto, Session>>,
+    user_map: RwLock<HashMap<String, Session>>,
+    log: Mutex<Option<LogSender>>,
     log_thread: thread::JoinHandle<()>,
+    watch: Mutex<Option<Sender<()>>>,
+    watch_thread: thread::JoinHandle<()>,
+}
+
+impl UserData {
+    fn auth_log(&self, args: fmt::Arguments) {
+        let log = self.log.lock().unwrap();
+        if let Some(ref log) = *log {
+            let mut output = String::new();
+            fmt::write(&mut output, args).unwrap();
+            log.send((Local::now(), output)).unwrap();
+        };
+    }
 }
 
 pub struct ConfigInfo {
-    last_check_time: SystemTime,
-    file_time: i64,
+    file_times: Vec<(String, misc::FileStamp)>,
+    version: usize,
+    hash: Option<String>,
+    last_failure: Option<SystemTime>,
+    status_file: Option<String>,
     config: Option<Box<config::Config>>,
 }
 
+/// Checks a config file without putting it in use, as a deployment does before a reload.
+/// Returns the warnings, or the error which prevents the file from loading,
+/// which is a `ConfigError` when it is located in the file.
+pub fn check_config(config_path: &str) -> Result<Vec<ConfigError>, Box<dyn std::error::Error>> {
+    Ok(config::read_from_file(config_path)?.warnings())
+}
+
+/// Claims of a verified token, kept for the lifetime of a connection.
+pub struct Session {
+    token: String,
+    username: Option<String>,
+    client_id: Option<String>,
+    claims: Value,
+    /// roles of the claims, as of `config_version`
+    roles: Vec<String>,
+    exp: Option<i64>,
+    kid: Option<String>,
+    config_version: usize,
+    /// templated paths of the acl, by index, expanded for the session; `None` when a value is missing
+    dadget_paths: HashMap<usize, Option<config::DadgetResourcePath>>,
+    mqtt_paths: HashMap<usize, Option<config::MqttResourcePath>>,
+    /// regexes of the templates which do not refer to the resource path, by pattern
+    regexes: Mutex<HashMap<String, Option<Regex>>>,
+}
+
+impl Session {
+    fn new(
+        token: &str,
+        username: Option<&str>,
+        client_id: Option<&str>,
+        token_data: TokenData<Value>,
+        config: &config::Config,
+        config_version: usize,
+    ) -> Session {
+        let mut session = Session {
+            token: token.to_string(),
+            username: username.map(|x| x.to_string()),
+            client_id: client_id.map(|x| x.to_string()),
+            exp: token_data.claims["exp"].as_i64(),
+            kid: token_data.header.kid,
+            claims: token_data.claims,
+            roles: Vec::new(),
+            config_version,
+            dadget_paths: HashMap::new(),
+            mqtt_paths: HashMap::new(),
+            regexes: Mutex::new(HashMap::new()),
+        };
+        if let Some(ref directory) = config.directory {
+            directory.merge(&mut session.claims);
+        }
+        for (index, acl) in config.acl.iter().enumerate() {
+            match &acl.resource {
+                config::Resource::Dadget(resource) => match resource.path.expand(&session) {
+                    Some(Cow::Borrowed(_)) => {}
+                    x => {
+                        let path = x.map(Cow::into_owned);
+                        session.dadget_paths.insert(index, path);
+                    }
+                },
+                config::Resource::Mqtt(resource) => match resource.path.expand(&session) {
+                    Some(Cow::Borrowed(_)) => {}
+                    x => {
+                        let path = x.map(Cow::into_owned);
+                        session.mqtt_paths.insert(index, path);
+                    }
+                },
+            }
+        }
+        session.roles = config
+            .roles
+            .iter()
+            .filter(|(_, subject)| subject.matches(&session, &Captures::new()))
+            .map(|(name, _)| name.clone())
+            .collect();
+        debug!("sub:{}, roles:{:?}", session.sub(), session.roles);
+        session
+    }
+
+    fn sub(&self) -> &str {
+        self.claims
+            .get("sub")
+            .and_then(|x| x.as_str())
+            .unwrap_or("no sub")
+    }
+
+    /// Returns the value of a placeholder `${name}` in templates.
+    /// `${path.<name>}` is a named group captured by the resource path.
+    fn placeholder<'a>(&'a self, name: &str, captures: &'a Captures) -> Option<&'a str> {
+        match name {
+            "clientid" => self.client_id.as_deref(),
+            "username" => self.username.as_deref(),
+            _ => match name.strip_prefix("path.") {
+                Some(x) => captures.get(x).map(|x| x.as_str()),
+                None => claim(&self.claims, name.strip_prefix("claims.").unwrap_or(name))
+                    .and_then(|x| x.as_str()),
+            },
+        }
+    }
+
+    /// Returns the path of the acl at `index`, with the placeholders filled in.
+    fn dadget_path<'a>(
+        &'a self,
+        index: usize,
+        path: &'a config::DadgetResourcePath,
+    ) -> Option<&'a config::DadgetResourcePath> {
+        match self.dadget_paths.get(&index) {
+            Some(x) => x.as_ref(),
+            None => Some(path),
+        }
+    }
+
+    /// Returns the path of the acl at `index`, with the placeholders filled in.
+    fn mqtt_path<'a>(
+        &'a self,
+        index: usize,
+        path: &'a config::MqttResourcePath,
+    ) -> Option<&'a config::MqttResourcePath> {
+        match self.mqtt_paths.get(&index) {
+            Some(x) => x.as_ref(),
+            None => Some(path),
+        }
+    }
+
+    fn is_expired(&self, leeway: u64) -> bool {
+        match self.exp {
+            Some(exp) => exp + (leeway as i64) < Local::now().timestamp(),
+            None => false,
+        }
+    }
+}
+
 #[repr(C)]
 pub struct mosquitto {}
 
@@ -116,6 +288,7 @@ pub struct mosquitto_acl_msg {
 }
 
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn proc_mosquitto_auth_plugin_init(
     user_data: *mut *mut UserData,
     opts: *const mosquitto_opt,
@@ -130,7 +303,7 @@ pub extern "C" fn proc_mosquitto_auth_plugin_init(
         opt_map.insert(opt_key.into_owned(), opt_value.into_owned());
     }
     let config_path = match opt_map.get(DEFAULT_CONFIG_PATH_OPT_KEY) {
-        Some(x) => &x,
+        Some(x) => x,
         None => DEFAULT_CONFIG_PATH,
     };
     let auth_log_file_name = match opt_map.get(DEFAULT_AUTH_LOG_FILE_NAME_OPT_KEY) {
@@ -138,17 +311,23 @@ pub extern "C" fn proc_mosquitto_auth_plugin_init(
         None => DEFAULT_AUTH_LOG_FILE_NAME.to_string(),
     };
     let log_file_name = match opt_map.get(DEFAULT_LOG_FILE_NAME_OPT_KEY) {
-        Some(x) => &x,
+        Some(x) => x,
         None => DEFAULT_LOG_FILE_NAME,
     };
     let log_level: &str = match opt_map.get(DEFAULT_LOG_LEVEL_OPT_KEY) {
-        Some(x) => &x,
+        Some(x) => x,
         None => "Info",
     };
+    let poll_interval = opt_map
+        .get(DEFAULT_CONFIG_POLL_INTERVAL_OPT_KEY)
+        .and_then(|x| u64::from_str(x).ok())
+        .unwrap_or(CONFIG_FILE_CHECK_INTERVAL);
 
     // init loggers
     let mut log_list: Vec<Box<dyn SharedLogger>> = vec![];
-    TermLogger::new(LevelFilter::Info, Config::default()).map(|x| log_list.push(x));
+    if let Some(x) = TermLogger::new(LevelFilter::Info, Config::default()) {
+        log_list.push(x);
+    }
     if let Ok(log_file) = OpenOptions::new()
         .append(true)
         .create(true)
@@ -188,10 +367,9 @@ pub extern "C" fn proc_mosquitto_auth_plugin_init(
             {
                 Ok(log_file) => {
                     let mut f = BufWriter::new(log_file);
-                    let _ = writeln!(f, "{} {}", time.format(LOG_DATE_FORMAT).to_string(), text);
+                    let _ = writeln!(f, "{} {}", time.format(LOG_DATE_FORMAT), text);
                     while let Ok((time, text)) = log_receiver.try_recv() {
-                        let _ =
-                            writeln!(f, "{} {}", time.format(LOG_DATE_FORMAT).to_string(), text);
+                        let _ = writeln!(f, "{} {}", time.format(LOG_DATE_FORMAT), text);
                     }
                 }
                 Err(_e) => continue,
@@ -200,14 +378,27 @@ pub extern "C" fn proc_mosquitto_auth_plugin_init(
         debug!("stop a log thread");
     });
 
-    let config_info = misc::update_config(config_path);
+    let status_file = opt_map.get(DEFAULT_CONFIG_STATUS_FILE_OPT_KEY).cloned();
+    let config_info = Arc::new(RwLock::new(misc::load_config(config_path, status_file)));
+
+    // init a config watcher
+    let (watch_sender, watch_receiver) = channel::<()>();
+    let watch_thread_handler = watch::watch_config(
+        config_path.to_string(),
+        config_info.clone(),
+        Some(Duration::from_secs(poll_interval)).filter(|x| !x.is_zero()),
+        watch_receiver,
+    );
 
     let config = Box::new(UserData {
         config_path: config_path.to_string(),
-        config_info: RwLock::new(config_info),
+        config_info,
         client_map: RwLock::new(HashMap::new()),
+        user_map: RwLock::new(HashMap::new()),
         log: Mutex::new(Some(log_sender.clone())),
         log_thread: log_thread_handler,
+        watch: Mutex::new(Some(watch_sender)),
+        watch_thread: watch_thread_handler,
     });
     unsafe {
         *user_data = Box::into_raw(config);
@@ -216,6 +407,7 @@ pub extern "C" fn proc_mosquitto_auth_plugin_init(
 }
 
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn proc_mosquitto_auth_plugin_cleanup(
     user_data: *mut UserData,
     _opts: *const mosquitto_opt,
@@ -224,6 +416,10 @@ pub extern "C" fn proc_mosquitto_auth_plugin_cleanup(
     debug!("proc_mosquitto_auth_plugin_cleanup");
     info!("stop plugin");
     let user_data = unsafe { Box::from_raw(user_data) };
+    {
+        user_data.watch.lock().unwrap().take();
+    }
+    user_data.watch_thread.join().unwrap();
     {
         user_data.log.lock().unwrap().take();
     }
@@ -233,6 +429,7 @@ pub extern "C" fn proc_mosquitto_auth_plugin_cleanup(
 }
 
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn proc_mosquitto_auth_security_init(
     user_data: *const UserData,
     _opts: *const mosquitto_opt,
@@ -242,7 +439,7 @@ pub extern "C" fn proc_mosquitto_auth_security_init(
     let user_data: &UserData = unsafe { &*user_data };
     debug!("proc_mosquitto_auth_security_init");
     if reload != 0 {
-        misc::no_check_config_update(&user_data);
+        misc::reload_config(&user_data.config_path, &user_data.config_info);
     }
     MOSQ_ERR_SUCCESS
 }
@@ -259,95 +456,186 @@ pub extern "C" fn proc_mosquitto_auth_security_cleanup(
 }
 
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn proc_mosquitto_auth_unpwd_check_v2(
     user_data: *const UserData,
     username: *const c_char,
-    _password: *const c_char,
+    password: *const c_char,
 ) -> c_int {
     debug!("proc_mosquitto_auth_unpwd_check_v2");
     let user_data: &UserData = unsafe { &*user_data };
-    proc_mosquitto_auth_unpwd_check(user_data, username)
+    let session = match proc_mosquitto_auth_unpwd_check(user_data, None, username, password) {
+        Ok(x) => x,
+        Err(e) => return e,
+    };
+    let config_info = user_data.config_info.read().unwrap();
+    let config = match config_info.config {
+        Some(ref x) => x,
+        None => return MOSQ_ERR_AUTH,
+    };
+    // the v2 interface has no client handle, so sessions are kept per username.
+    // The username has to be the token, or bound to the token by `usernameClaim`,
+    // since another client could take over the session with its own token.
+    let key = session
+        .username
+        .clone()
+        .unwrap_or_else(|| session.token.clone());
+    if key != session.token && config.username_claim.is_none() {
+        warn!(
+            "username:{}, the v2 interface requires usernameClaim unless the token is the username",
+            key
+        );
+        return MOSQ_ERR_AUTH;
+    }
+    let mut user_map = user_data.user_map.write().unwrap();
+    // a session stays during the grace period
+    let leeway = config.validation.leeway + config.session.grace_period;
+    user_map.retain(|_, x| !x.is_expired(leeway));
+    user_map.insert(key, session);
+    MOSQ_ERR_SUCCESS
 }
 
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn proc_mosquitto_auth_unpwd_check_v3(
     user_data: *const UserData,
     client: *const mosquitto,
+    client_id: *const c_char,
     username: *const c_char,
-    _password: *const c_char,
+    password: *const c_char,
 ) -> c_int {
     debug!("proc_mosquitto_auth_unpwd_check_v3");
     let user_data: &UserData = unsafe { &*user_data };
-    let result = proc_mosquitto_auth_unpwd_check(user_data, username);
+    let result = proc_mosquitto_auth_unpwd_check(user_data, c_str(client_id), username, password);
 
-    // save username
+    // save the verified claims
     let mut client_map = user_data.client_map.write().unwrap();
-    if result == MOSQ_ERR_SUCCESS {
-        let username = unsafe { CStr::from_ptr(username) };
-        client_map.insert(client, username.to_string_lossy().to_string());
-    } else {
-        client_map.remove(&client);
+    match result {
+        Ok(session) => {
+            client_map.insert(client, session);
+            MOSQ_ERR_SUCCESS
+        }
+        Err(e) => {
+            client_map.remove(&client);
+            e
+        }
     }
-    result
 }
 
-fn proc_mosquitto_auth_unpwd_check(user_data: &UserData, token: *const c_char) -> c_int {
-    misc::check_config_update(&user_data);
-    if token == NULL {
-        return MOSQ_ERR_AUTH;
-    }
-    let token = match unsafe { CStr::from_ptr(token) }.to_str() {
-        Ok(x) => x,
-        Err(e) => {
-            warn!("illegal jwt:{}", e);
-            return MOSQ_ERR_AUTH;
-        }
-    };
-    debug!("jwt {}", token);
+fn proc_mosquitto_auth_unpwd_check(
+    user_data: &UserData,
+    client_id: Option<&str>,
+    username: *const c_char,
+    password: *const c_char,
+) -> Result<Session, c_int> {
+    let username = c_str(username);
+    let password = c_str(password);
     let config_info = user_data.config_info.read().unwrap();
     let config = match config_info.config {
         Some(ref x) => x,
         None => {
-            return MOSQ_ERR_AUTH;
+            return Err(MOSQ_ERR_AUTH);
+        }
+    };
+
+    let token = match config.token_source {
+        config::TokenSource::Username => username,
+        config::TokenSource::Password => password,
+        config::TokenSource::Both => password.filter(|x| !x.is_empty()).or(username),
+    };
+    let token = match token {
+        Some(x) => x,
+        None => {
+            warn!("username:{:?}, no jwt", username);
+            return Err(MOSQ_ERR_AUTH);
         }
     };
+    debug!("jwt {}", token);
 
-    let token_data = match decode::<Value>(
-        token.as_ref(),
-        config.key.as_bytes(),
-        &Validation::new(Algorithm::HS256),
-    ) {
+    let token_data = match config.verify(token) {
         Ok(x) => x,
         Err(e) => {
             warn!("jwt:{}, {}", token, e);
-            return MOSQ_ERR_AUTH;
+            return Err(MOSQ_ERR_AUTH);
         }
     };
     debug!("claims:{}", token_data.claims);
 
-    let sub = token_data
-        .claims
-        .get("sub")
-        .and_then(|x| x.as_str())
-        .unwrap_or("no sub");
-    let log = user_data.log.lock().unwrap();
-    if let Some(ref log) = *log {
-        let mut output = String::new();
-        fmt::write(&mut output, format_args!("AUTH {}", sub)).unwrap();
-        log.send((Local::now(), output)).unwrap();
-    };
-    MOSQ_ERR_SUCCESS
+    let session = Session::new(
+        token,
+        username,
+        client_id,
+        token_data,
+        config,
+        config_info.version,
+    );
+    if let Some(ref claim) = config.username_claim {
+        // a missing username does not match a missing claim
+        if username.is_none() || username != session.claims[claim].as_str() {
+            warn!(
+                "sub:{}, username:{:?} does not match claim {}",
+                session.sub(),
+                username,
+                claim
+            );
+            return Err(MOSQ_ERR_AUTH);
+        }
+    }
+    // the v2 interface checks the client id on each ACL check instead
+    if let Some(client_id) = client_id {
+        if !check_client_id(config, client_id, &session) {
+            return Err(MOSQ_ERR_AUTH);
+        }
+    }
+    if let Some(reason) = config.revoked.check(&session.claims) {
+        warn!("sub:{}, token revoked by {}", session.sub(), reason);
+        user_data.auth_log(format_args!("REVOKED {} {}", session.sub(), reason));
+        return Err(MOSQ_ERR_AUTH);
+    }
+    user_data.auth_log(format_args!("AUTH {}", session.sub()));
+    Ok(session)
+}
+
+/// Checks the client id against `clientIdBinding`.
+fn check_client_id(config: &config::Config, client_id: &str, session: &Session) -> bool {
+    match config.client_id_binding {
+        Some(ref binding) if !binding.check(client_id, session) => {
+            warn!(
+                "sub:{}, clientid:{} does not match the binding",
+                session.sub(),
+                client_id
+            );
+            false
+        }
+        _ => true,
+    }
+}
+
+/// Returns the string of a C string, or `None` when it is NULL or not UTF-8.
+fn c_str<'a>(x: *const c_char) -> Option<&'a str> {
+    if x == NULL {
+        return None;
+    }
+    match unsafe { CStr::from_ptr(x) }.to_str() {
+        Ok(x) => Some(x),
+        Err(e) => {
+            warn!("illegal string:{}", e);
+            None
+        }
+    }
 }
 
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn proc_mosquitto_auth_acl_check_v2(
     user_data: *const UserData,
-    _clientid: *const c_char,
+    client_id: *const c_char,
     username: *const c_char,
     topic: *const c_char,
     access: c_int,
 ) -> c_int {
     debug!("proc_mosquitto_auth_acl_check_v2");
+    let user_data: &UserData = unsafe { &*user_data };
     if username == NULL {
         return MOSQ_ERR_ACL_DENIED;
     }
@@ -357,10 +645,29 @@ pub extern "C" fn proc_mosquitto_auth_acl_check_v2(
             return MOSQ_ERR_ACL_DENIED;
         }
     };
-    proc_mosquitto_auth_acl_check(user_data, username, topic, access)
+    let config_info = user_data.config_info.read().unwrap();
+    let result = with_session(
+        &user_data.user_map,
+        username.to_string(),
+        Some(username),
+        &config_info,
+        |config, session| {
+            if !check_client_id(config, c_str(client_id).unwrap_or(""), session) {
+                return MOSQ_ERR_ACL_DENIED;
+            }
+            proc_mosquitto_auth_acl_check(user_data, config, session, topic, None, access)
+        },
+    );
+    // the v2 interface cannot disconnect a client
+    if result == CHIPIN_ERR_SESSION_EXPIRED {
+        MOSQ_ERR_ACL_DENIED
+    } else {
+        result
+    }
 }
 
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn proc_mosquitto_auth_acl_check_v3(
     user_data: *const UserData,
     access: c_int,
@@ -369,164 +676,665 @@ pub extern "C" fn proc_mosquitto_auth_acl_check_v3(
 ) -> c_int {
     debug!("proc_mosquitto_auth_acl_check_v3");
     let user_data: &UserData = unsafe { &*user_data };
-    let client_map = user_data.client_map.read().unwrap();
-    match client_map.get(&client) {
-        Some(username) => {
-            proc_mosquitto_auth_acl_check(user_data, username, unsafe { (*msg).topic }, access)
+    let config_info = user_data.config_info.read().unwrap();
+    with_session(
+        &user_data.client_map,
+        client,
+        None,
+        &config_info,
+        |config, session| {
+            proc_mosquitto_auth_acl_check(
+                user_data,
+                config,
+                session,
+                unsafe { (*msg).topic },
+                Some(unsafe { &*msg }),
+                access,
+            )
+        },
+    )
+}
+
+/// Runs `f` with the verified session of `key`.
+/// The token is verified again only when the config or the key set has been reloaded
+/// since the session was created. Without a session, `username` is verified as the token.
+fn with_session<K, F>(
+    map: &RwLock<HashMap<K, Session>>,
+    key: K,
+    username: Option<&str>,
+    config_info: &ConfigInfo,
+    f: F,
+) -> c_int
+where
+    K: Eq + Hash,
+    F: FnOnce(&config::Config, &Session) -> c_int,
+{
+    let config = match config_info.config {
+        Some(ref x) => x,
+        None => {
+            return MOSQ_ERR_ACL_DENIED;
         }
-        None => MOSQ_ERR_ACL_DENIED,
-    }
+    };
+    let (token, username, client_id) = {
+        let map = map.read().unwrap();
+        match map.get(&key) {
+            Some(session) if session.config_version == config_info.version => {
+                return f(config, session);
+            }
+            Some(session) => (
+                session.token.clone(),
+                session.username.clone(),
+                session.client_id.clone(),
+            ),
+            None => match username {
+                Some(x) => (x.to_string(), Some(x.to_string()), None),
+                None => {
+                    return MOSQ_ERR_ACL_DENIED;
+                }
+            },
+        }
+    };
+    let session = match config.verify_session(&token) {
+        Ok(x) => Session::new(
+            &token,
+            username.as_deref(),
+            client_id.as_deref(),
+            x,
+            config,
+            config_info.version,
+        ),
+        Err(e) => {
+            warn!("jwt:{}, {}", token, e);
+            map.write().unwrap().remove(&key);
+            return MOSQ_ERR_ACL_DENIED;
+        }
+    };
+    debug!(
+        "verified again sub:{}, kid:{:?}",
+        session.sub(),
+        session.kid
+    );
+    let result = f(config, &session);
+    map.write().unwrap().insert(key, session);
+    result
 }
 
 fn proc_mosquitto_auth_acl_check(
-    user_data: *const UserData,
-    token: &str,
+    user_data: &UserData,
+    config: &config::Config,
+    session: &Session,
     topic: *const c_char,
+    msg: Option<&mosquitto_acl_msg>,
     access: c_int,
 ) -> c_int {
-    let user_data: &UserData = unsafe { &*user_data };
-    misc::check_config_update(&user_data);
     let topic = match unsafe { CStr::from_ptr(topic) }.to_str() {
         Ok(x) => x,
         Err(e) => {
-            warn!("jwt:{}, illegal topic:{}", token, e);
+            warn!("jwt:{}, illegal topic:{}", session.token, e);
             return MOSQ_ERR_ACL_DENIED;
         }
     };
-    debug!("jwt {}", token);
+    debug!("jwt {}", session.token);
     debug!("topic {}", topic);
 
-    let config_info = user_data.config_info.read().unwrap();
-    let config = match config_info.config {
-        Some(ref x) => x,
-        None => {
-            return MOSQ_ERR_ACL_DENIED;
+    let sub = session.sub();
+    if session.is_expired(config.validation.leeway) {
+        if session.is_expired(config.validation.leeway + config.session.grace_period) {
+            warn!("sub:{}, token expired", sub);
+            return match config.session.on_expiry {
+                config::ExpiryAction::Deny => MOSQ_ERR_ACL_DENIED,
+                config::ExpiryAction::Disconnect => CHIPIN_ERR_SESSION_EXPIRED,
+            };
         }
-    };
-
-    let token_data = match decode::<Value>(
-        token.as_ref(),
-        config.key.as_bytes(),
-        &Validation::new(Algorithm::HS256),
-    ) {
-        Ok(x) => x,
-        Err(e) => {
-            warn!("jwt:{}, {}", token, e);
-            return MOSQ_ERR_ACL_DENIED;
-        }
-    };
-    let sub = token_data
-        .claims
-        .get("sub")
-        .and_then(|x| x.as_str())
-        .unwrap_or("no sub");
-    let (db_name, subset_name) = if let Some(caps) = REGEX_PATH_TRANSACTION.captures(topic) {
-        (
-            Some(
-                percent_decode(caps.get(1).unwrap().as_str().as_bytes())
-                    .decode_utf8()
-                    .unwrap(),
-            ),
-            None,
-        )
-    } else if let Some(caps) = REGEX_PATH_SUBSET_TRANSACTION.captures(topic) {
-        (
-            Some(
-                percent_decode(caps.get(1).unwrap().as_str().as_bytes())
-                    .decode_utf8()
-                    .unwrap(),
-            ),
-            Some(
-                percent_decode(caps.get(2).unwrap().as_str().as_bytes())
-                    .decode_utf8()
-                    .unwrap(),
-            ),
-        )
-    } else {
-        (None, None)
-    };
+        info!("sub:{}, token expired, in grace period", sub);
+    }
+    if let Some(reason) = config.revoked.check(&session.claims) {
+        warn!("sub:{}, token revoked by {}", sub, reason);
+        user_data.auth_log(format_args!("REVOKED {} {}", sub, reason));
+        return MOSQ_ERR_ACL_DENIED;
+    }
+    let segments = config.dadget_topics.iter().find_map(|x| x.segments(topic));
     let mode = match access {
         MOSQ_ACL_READ => "READ",
         MOSQ_ACL_WRITE => "WRITE",
         MOSQ_ACL_SUBSCRIBE => "SUBSCRIBE",
         _ => "ANOTHER",
     };
-    for acl in &config.acl {
-        match &acl.resource {
-            config::Resource::Dadget(resource) => match db_name {
-                None => {}
-                Some(ref db_name) => {
-                    if resource
-                        .path
-                        .check_path(db_name, subset_name.as_ref().map(|x| x.as_ref()))
-                    {
-                        let result = check_accesses(&token_data.claims, &acl.accesses, access);
-                        if result == MOSQ_ERR_SUCCESS {
-                            let log = user_data.log.lock().unwrap();
-                            if let Some(ref log) = *log {
-                                let mut output = String::new();
-                                fmt::write(&mut output, format_args!("{} {} {}", mode, topic, sub))
-                                    .unwrap();
-                                log.send((Local::now(), output)).unwrap();
-                            };
-                            return result;
-                        }
+    // effect, the name of the acl or the scope, and the role
+    let mut decision: Option<(config::Effect, &str, Option<&str>)> = None;
+    let mut violations = Vec::new();
+    'acl: for (index, acl) in config.acl.iter().enumerate() {
+        let captures = match &acl.resource {
+            config::Resource::Dadget(resource) => {
+                match (&segments, session.dadget_path(index, &resource.path)) {
+                    (Some(segments), Some(path)) if path.check_path(segments) => {
+                        Some(path.captures(segments))
                     }
+                    _ => None,
+                }
+            }
+            config::Resource::Mqtt(resource) => match session.mqtt_path(index, &resource.path) {
+                Some(path) if access == MOSQ_ACL_SUBSCRIBE && path.check_filter(topic) => {
+                    Some(path.captures(topic))
+                }
+                Some(path) if access != MOSQ_ACL_SUBSCRIBE && path.check_path(topic) => {
+                    Some(path.captures(topic))
                 }
+                _ => None,
             },
-            config::Resource::Mqtt(resource) => {
-                if resource.path.check_path(topic) {
-                    let result = check_accesses(&token_data.claims, &acl.accesses, access);
-                    if result == MOSQ_ERR_SUCCESS {
-                        let log = user_data.log.lock().unwrap();
-                        if let Some(ref log) = *log {
-                            let mut output = String::new();
-                            fmt::write(&mut output, format_args!("{} {} {}", mode, topic, sub))
-                                .unwrap();
-                            log.send((Local::now(), output)).unwrap();
-                        };
-                        return result;
-                    }
+        };
+        let captures = match captures {
+            Some(x) => x,
+            None => continue,
+        };
+        for (effect, role) in applicable_effects(
+            session,
+            &captures,
+            acl,
+            access,
+            msg,
+            config.legacy_subscribe,
+            &mut violations,
+        ) {
+            let is_final = config.combining.is_final(effect);
+            if is_final || decision.is_none() {
+                decision = Some((effect, &acl.name, role));
+            }
+            if is_final {
+                break 'acl;
+            }
+        }
+    }
+    if let Some(ref scopes) = config.scopes {
+        if !decision.is_some_and(|(effect, _, _)| config.combining.is_final(effect)) {
+            if let Some(scope) = scopes.grant(session, topic, access) {
+                if decision.is_none() || config.combining.is_final(config::Effect::Allow) {
+                    decision = Some((config::Effect::Allow, scope, None));
                 }
             }
-            config::Resource::Other => {}
         }
     }
-    warn!("sub:{}, No {} permission topic:{}", sub, mode, topic);
-    MOSQ_ERR_ACL_DENIED
+    match decision {
+        Some((config::Effect::Allow, _, _))
+            if config
+                .token_acl
+                .as_ref()
+                .is_some_and(|x| !x.permits(session, topic, access)) =>
+        {
+            warn!("sub:{}, {} topic:{} not in the token acl", sub, mode, topic);
+            MOSQ_ERR_ACL_DENIED
+        }
+        Some((config::Effect::Allow, name, None)) => {
+            debug!("granted by {}", name);
+            user_data.auth_log(format_args!("{} {} {}", mode, topic, sub));
+            MOSQ_ERR_SUCCESS
+        }
+        Some((config::Effect::Allow, name, Some(role))) => {
+            debug!("granted by {} to role {}", name, role);
+            user_data.auth_log(format_args!("{} {} {} {}", mode, topic, sub, role));
+            MOSQ_ERR_SUCCESS
+        }
+        Some((config::Effect::Deny, name, _)) => {
+            warn!("sub:{}, {} topic:{} denied by {}", sub, mode, topic, name);
+            MOSQ_ERR_ACL_DENIED
+        }
+        None => {
+            for x in violations {
+                warn!("sub:{}, {} topic:{} violates {}", sub, mode, topic, x);
+            }
+            warn!("sub:{}, No {} permission topic:{}", sub, mode, topic);
+            MOSQ_ERR_ACL_DENIED
+        }
+    }
 }
 
-impl config::DadgetResourcePath {
-    fn check_path(&self, db_name: &str, subset_name: Option<&str>) -> bool {
+enum VerifyError {
+    Jwt(jsonwebtoken::errors::Error),
+    NoIat,
+    Lifetime(i64),
+}
+
+impl From<jsonwebtoken::errors::Error> for VerifyError {
+    fn from(e: jsonwebtoken::errors::Error) -> Self {
+        VerifyError::Jwt(e)
+    }
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            config::DadgetResourcePath::Str(x) => match x.len() {
-                0 => true,
-                1 => x[0] == db_name,
-                2 => {
-                    x[0] == db_name
-                        && match subset_name {
-                            Some(subset_name) => x[1] == *subset_name,
-                            None => false,
-                        }
-                }
-                _ => false,
+            VerifyError::Jwt(e) => match e.kind() {
+                ErrorKind::ExpiredSignature => write!(f, "token expired"),
+                ErrorKind::ImmatureSignature => write!(f, "token not yet valid (nbf)"),
+                ErrorKind::InvalidIssuer => write!(f, "issuer mismatch"),
+                ErrorKind::InvalidAudience => write!(f, "audience mismatch"),
+                ErrorKind::MissingRequiredClaim(x) => write!(f, "missing claim {}", x),
+                ErrorKind::InvalidSignature => write!(f, "invalid signature"),
+                ErrorKind::InvalidAlgorithm => write!(f, "algorithm not allowed"),
+                _ => write!(f, "{}", e),
             },
-            config::DadgetResourcePath::Regex(x) => match x.len() {
-                1 => x[0].is_match(db_name),
-                2 => {
-                    x[0].is_match(db_name)
-                        && match subset_name {
-                            Some(subset_name) => x[1].is_match(subset_name),
-                            None => false,
-                        }
+            VerifyError::NoIat => write!(f, "missing claim iat"),
+            VerifyError::Lifetime(x) => write!(f, "token lifetime too long ({}s)", x),
+        }
+    }
+}
+
+impl config::Config {
+    fn verify(&self, token: &str) -> Result<TokenData<Value>, VerifyError> {
+        self.verify_token(token, true)
+    }
+
+    /// Verifies the token of an existing session.
+    /// `exp` is left to the session policy, which allows a grace period.
+    fn verify_session(&self, token: &str) -> Result<TokenData<Value>, VerifyError> {
+        self.verify_token(token, false)
+    }
+
+    fn verify_token(
+        &self,
+        token: &str,
+        validate_exp: bool,
+    ) -> Result<TokenData<Value>, VerifyError> {
+        let header = decode_header(token)?;
+        if !self.allowed_algorithms().contains(&header.alg) {
+            return Err(jsonwebtoken::errors::Error::from(ErrorKind::InvalidAlgorithm).into());
+        }
+        // select by kid, or try every key of the algorithm when the token has no kid.
+        // A key without kid, such as the legacy `key`, is tried for any token.
+        let mut keys = self
+            .keys
+            .iter()
+            .filter(|x| {
+                x.alg == header.alg
+                    && (header.kid.is_none() || x.kid.is_none() || x.kid == header.kid)
+            })
+            .peekable();
+        if keys.peek().is_none() {
+            warn!("no key for alg:{:?}, kid:{:?}", header.alg, header.kid);
+        }
+        let mut result = Err(ErrorKind::InvalidSignature.into());
+        for key in keys {
+            let mut validation = self.validation.to_validation(key.alg);
+            validation.validate_exp = validate_exp;
+            result = decode::<Value>(token, &key.key, &validation);
+            match result {
+                Err(ref e) if *e.kind() == ErrorKind::InvalidSignature => {}
+                _ => break,
+            }
+        }
+        let token_data = result?;
+        if let Some(max_lifetime) = self.validation.max_lifetime {
+            let lifetime = match (
+                token_data.claims["exp"].as_i64(),
+                token_data.claims["iat"].as_i64(),
+            ) {
+                (Some(exp), Some(iat)) => exp - iat,
+                _ => return Err(VerifyError::NoIat),
+            };
+            if lifetime > max_lifetime as i64 {
+                return Err(VerifyError::Lifetime(lifetime));
+            }
+        }
+        Ok(token_data)
+    }
+}
+
+impl config::Combining {
+    /// Returns true when the effect decides without the rest of the accesses.
+    fn is_final(self, effect: config::Effect) -> bool {
+        matches!(
+            (self, effect),
+            (config::Combining::FirstApplicable, _)
+                | (config::Combining::DenyOverrides, config::Effect::Deny)
+                | (config::Combining::PermitOverrides, config::Effect::Allow)
+        )
+    }
+}
+
+impl config::Directory {
+    /// Merges the attributes of the subject into the claims.
+    /// An attribute overrides the claim, except the registered claims.
+    fn merge(&self, claims: &mut Value) {
+        let attributes = match claims["sub"].as_str().and_then(|x| self.entries.get(x)) {
+            Some(x) => x,
+            None => return,
+        };
+        if let Value::Object(claims) = claims {
+            for (name, value) in attributes {
+                if REGISTERED_CLAIMS.contains(&name.as_str()) {
+                    continue;
                 }
-                _ => false,
+                claims.insert(name.clone(), value.clone());
+            }
+        }
+    }
+}
+
+impl config::TokenAcl {
+    fn permits(&self, session: &Session, topic: &str, access: c_int) -> bool {
+        let entries = match claim(&session.claims, &self.claim) {
+            Some(Value::Array(x)) => x,
+            Some(_) => {
+                warn!(
+                    "sub:{}, claim {} is not an array",
+                    session.sub(),
+                    self.claim
+                );
+                return false;
+            }
+            None => return !self.required,
+        };
+        entries.iter().any(|entry| {
+            let filter = match entry["topic"].as_str() {
+                Some(x) if topic::validate_filter(x).is_ok() => x,
+                _ => {
+                    debug!("sub:{}, illegal token acl {}", session.sub(), entry);
+                    return false;
+                }
+            };
+            let ops = entry["ops"].as_array().map(|x| x.as_slice()).unwrap_or(&[]);
+            topic::contains(filter, topic)
+                && ops
+                    .iter()
+                    .filter_map(|x| x.as_str())
+                    .any(|x| match_access(x, access))
+        })
+    }
+}
+
+impl config::ScopeGrammar {
+    /// Returns the scope which grants the access to the topic.
+    fn grant<'a>(&self, session: &'a Session, topic: &str, access: c_int) -> Option<&'a str> {
+        let scopes: Vec<&str> = match claim(&session.claims, &self.claim)? {
+            Value::String(x) => x.split_whitespace().collect(),
+            Value::Array(x) => x.iter().filter_map(|x| x.as_str()).collect(),
+            _ => {
+                warn!("sub:{}, claim {} is not a scope", session.sub(), self.claim);
+                return None;
+            }
+        };
+        scopes.into_iter().find(|scope| {
+            let rest = if self.prefix.is_empty() {
+                scope
+            } else {
+                match scope
+                    .strip_prefix(self.prefix.as_str())
+                    .and_then(|x| x.strip_prefix(self.separator.as_str()))
+                {
+                    Some(x) => x,
+                    None => return false,
+                }
+            };
+            let mut parts = rest.splitn(2, self.separator.as_str());
+            let (operation, filter) = match (parts.next(), parts.next()) {
+                (Some(x), Some(y)) => (x, y),
+                _ => return false,
+            };
+            let operation = match self.operations.get(operation) {
+                Some(x) => x.as_str(),
+                None if self.operations.is_empty() => operation,
+                None => return false,
+            };
+            if let Err(e) = topic::validate_filter(filter) {
+                debug!("sub:{}, scope {}: {}", session.sub(), scope, e);
+                return false;
+            }
+            // the topic filter as issued, rather than a prefix as a resource path
+            topic::contains(filter, topic) && match_access(operation, access)
+        })
+    }
+}
+
+impl config::ClientIdBinding {
+    fn check(&self, client_id: &str, session: &Session) -> bool {
+        match *self {
+            config::ClientIdBinding::Claim(ref claim) => {
+                session.claims[claim].as_str() == Some(client_id)
+            }
+            config::ClientIdBinding::Regex(ref regex) => regex.is_match(client_id),
+            config::ClientIdBinding::Template(ref template) => {
+                match template.to_regex(session, &Captures::new()) {
+                    Some(regex) => regex.is_match(client_id),
+                    None => false,
+                }
+            }
+        }
+    }
+}
+
+impl config::Template {
+    /// Fills in the placeholders with the values converted by `escape`.
+    /// `None` if a value is missing or rejected by `escape`.
+    fn expand<F>(&self, session: &Session, captures: &Captures, escape: F) -> Option<String>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut result = String::new();
+        for part in &self.0 {
+            match part {
+                config::TemplatePart::Text(x) => result.push_str(x),
+                config::TemplatePart::Var(name) => {
+                    let value = session.placeholder(name, captures).and_then(&escape);
+                    if value.is_none() {
+                        debug!("sub:{}, no value for ${{{}}}", session.sub(), name);
+                    }
+                    result.push_str(&value?);
+                }
+            }
+        }
+        Some(result)
+    }
+
+    /// Compiles the template as a regex, in which the values are literals.
+    /// The session keeps the regex unless a value is captured by the resource path,
+    /// which differs by topic.
+    fn to_regex(&self, session: &Session, captures: &Captures) -> Option<Regex> {
+        let pattern = self.expand(session, captures, |x| Some(regex::escape(x)))?;
+        if self
+            .0
+            .iter()
+            .any(|x| matches!(x, config::TemplatePart::Var(name) if name.starts_with("path.")))
+        {
+            return Regex::new(&pattern).ok();
+        }
+        let mut regexes = session.regexes.lock().unwrap();
+        regexes
+            .entry(pattern)
+            .or_insert_with_key(|x| Regex::new(x).ok())
+            .clone()
+    }
+}
+
+impl config::ClaimCondition {
+    /// Returns an error when the type of the claim does not fit the condition.
+    fn check(&self, value: &Value, session: &Session, captures: &Captures) -> Result<bool, String> {
+        if let Value::Array(values) = value {
+            let mut error = None;
+            for x in values {
+                match self.check(x, session, captures) {
+                    Ok(true) => return Ok(true),
+                    Ok(false) => {}
+                    Err(e) => error = error.or(Some(e)),
+                }
+            }
+            return error.map_or(Ok(false), Err);
+        }
+        match self {
+            config::ClaimCondition::Regex(x) => match value.as_str() {
+                Some(value) => Ok(x.is_match(value, session, captures)),
+                None => Err(format!("{} is not a string", value)),
+            },
+            config::ClaimCondition::Equals(x) => Ok(match (x.as_f64(), value.as_f64()) {
+                (Some(x), Some(y)) => x == y,
+                _ => x == value,
+            }),
+            config::ClaimCondition::Range { min, max } => match value.as_f64() {
+                Some(x) => Ok(min.is_none_or(|min| x >= min) && max.is_none_or(|max| x <= max)),
+                None => Err(format!("{} is not a number", value)),
             },
         }
     }
 }
 
+impl config::SubjectRegex {
+    fn is_match(&self, value: &str, session: &Session, captures: &Captures) -> bool {
+        match self {
+            config::SubjectRegex::Regex(x) => x.is_match(value),
+            config::SubjectRegex::Template(x) => match x.to_regex(session, captures) {
+                Some(x) => x.is_match(value),
+                None => false,
+            },
+        }
+    }
+}
+
+impl config::Revocation {
+    /// Returns the kind of the entry that revokes the token.
+    fn check(&self, claims: &Value) -> Option<&'static str> {
+        if let Some(jti) = claims["jti"].as_str() {
+            if self.jti.contains(jti) {
+                return Some("jti");
+            }
+        }
+        let sub = claims["sub"].as_str()?;
+        if self.sub.contains(sub) {
+            return Some("sub");
+        }
+        match self.issued_before.get(sub) {
+            // a token without iat cannot prove it was issued after the revocation
+            Some(time) if claims["iat"].as_i64().is_none_or(|iat| iat < *time) => {
+                Some("issuedBefore")
+            }
+            _ => None,
+        }
+    }
+}
+
+impl config::ClaimValidation {
+    fn to_validation(&self, alg: Algorithm) -> Validation {
+        let mut validation = Validation::new(alg);
+        validation.leeway = self.leeway;
+        validation.validate_nbf = self.nbf;
+        validation.validate_aud = self.aud.is_some();
+        if let Some(ref iss) = self.iss {
+            validation.set_issuer(iss);
+            validation.required_spec_claims.insert("iss".to_string());
+        }
+        if let Some(ref aud) = self.aud {
+            validation.set_audience(aud);
+            validation.required_spec_claims.insert("aud".to_string());
+        }
+        validation
+    }
+}
+
+impl config::DadgetTopic {
+    /// Returns the segments of a topic in this layout.
+    fn segments(&self, topic: &str) -> Option<Segments> {
+        let levels: Vec<_> = topic.split('/').collect();
+        if levels.len() != self.0.len() {
+            return None;
+        }
+        let mut segments = Segments::new();
+        for (level, x) in self.0.iter().zip(levels) {
+            match level {
+                config::DadgetTopicLevel::Literal(literal) if literal == x => {}
+                config::DadgetTopicLevel::Segment(name) if !x.is_empty() => {
+                    let value = percent_decode(x.as_bytes()).decode_utf8().ok()?;
+                    segments.insert(name.clone(), value.into_owned());
+                }
+                _ => return None,
+            }
+        }
+        Some(segments)
+    }
+}
+
+impl config::DadgetResourcePath {
+    /// Fills in the placeholders of the templates.
+    fn expand(&self, session: &Session) -> Option<Cow<'_, config::DadgetResourcePath>> {
+        if !self.0.iter().any(|(_, x)| {
+            matches!(
+                x,
+                config::DadgetSegment::StrTemplate(_) | config::DadgetSegment::RegexTemplate(_)
+            )
+        }) {
+            return Some(Cow::Borrowed(self));
+        }
+        let segments =
+            self.0
+                .iter()
+                .map(|(name, x)| {
+                    let x = match x {
+                        config::DadgetSegment::StrTemplate(x) => config::DadgetSegment::Str(
+                            x.expand(session, &Captures::new(), |x| Some(x.to_string()))?,
+                        ),
+                        config::DadgetSegment::RegexTemplate(x) => {
+                            config::DadgetSegment::Regex(x.to_regex(session, &Captures::new())?)
+                        }
+                        x => x.clone(),
+                    };
+                    Some((name.clone(), x))
+                })
+                .collect::<Option<Vec<_>>>()?;
+        Some(Cow::Owned(config::DadgetResourcePath(segments)))
+    }
+
+    /// Returns the named groups of the regex segments which match.
+    fn captures(&self, segments: &Segments) -> Captures {
+        let mut captures = Captures::new();
+        for (name, x) in &self.0 {
+            if let (config::DadgetSegment::Regex(regex), Some(value)) = (x, segments.get(name)) {
+                add_captures(&mut captures, regex, value);
+            }
+        }
+        captures
+    }
+
+    /// Every segment in the path has to be in the topic and match.
+    fn check_path(&self, segments: &Segments) -> bool {
+        self.0
+            .iter()
+            .all(|(name, x)| match (x, segments.get(name)) {
+                (config::DadgetSegment::Str(x), Some(value)) => x == value,
+                (config::DadgetSegment::Regex(x), Some(value)) => x.is_match(value),
+                // templates are expanded beforehand
+                _ => false,
+            })
+    }
+}
+
 impl config::MqttResourcePath {
+    /// Fills in the placeholders of a template.
+    /// A value must be a single topic level, so that it cannot add levels or wildcards.
+    fn expand(&self, session: &Session) -> Option<Cow<'_, config::MqttResourcePath>> {
+        match self {
+            config::MqttResourcePath::StrTemplate(x) => {
+                let path = x.expand(session, &Captures::new(), |x| {
+                    if topic::is_level(x) {
+                        Some(x.to_string())
+                    } else {
+                        None
+                    }
+                })?;
+                Some(Cow::Owned(config::MqttResourcePath::from_path(&path)))
+            }
+            config::MqttResourcePath::RegexTemplate(x) => Some(Cow::Owned(
+                config::MqttResourcePath::Regex(x.to_regex(session, &Captures::new())?),
+            )),
+            x => Some(Cow::Borrowed(x)),
+        }
+    }
+
+    /// Returns the named groups of a regex path which matches.
+    fn captures(&self, topic: &str) -> Captures {
+        let mut captures = Captures::new();
+        if let config::MqttResourcePath::Regex(x) = self {
+            add_captures(&mut captures, x, topic);
+        }
+        captures
+    }
+
     fn check_path(&self, topic: &str) -> bool {
         match self {
             config::MqttResourcePath::Str(x) => {
@@ -542,50 +1350,253 @@ impl config::MqttResourcePath {
                     topic.starts_with((x.to_string() + "/").as_str())
                 }
             }
+            config::MqttResourcePath::Filter(x) => topic::contains(x, topic),
             config::MqttResourcePath::Regex(x) => x.is_match(topic),
+            // expanded beforehand
+            config::MqttResourcePath::StrTemplate(_)
+            | config::MqttResourcePath::RegexTemplate(_) => false,
+        }
+    }
+
+    /// Checks a subscription filter, which has to be contained in the path.
+    fn check_filter(&self, filter: &str) -> bool {
+        match self {
+            // a plain path is the prefix of the topics
+            config::MqttResourcePath::Str(x) => {
+                topic::contains(&format!("{}/#", x.trim_end_matches('/')), filter)
+            }
+            config::MqttResourcePath::Filter(x) => topic::contains(x, filter),
+            config::MqttResourcePath::Regex(x) => x.is_match(filter),
+            // expanded beforehand
+            config::MqttResourcePath::StrTemplate(_)
+            | config::MqttResourcePath::RegexTemplate(_) => false,
+        }
+    }
+}
+
+fn add_captures(captures: &mut Captures, regex: &Regex, text: &str) {
+    if let Some(caps) = regex.captures(text) {
+        for name in regex.capture_names().flatten() {
+            if let Some(x) = caps.name(name) {
+                captures.insert(name.to_string(), x.as_str().to_string());
+            }
         }
     }
 }
 
-fn check_accesses(
-    claims: &serde_json::Value,
-    accesses: &Vec<config::Accesses>,
+/// Returns the effects of the accesses that apply to the claims, in order,
+/// with the role which the access applies to.
+/// An access does not apply to a published message which violates its constraints.
+fn applicable_effects<'a>(
+    session: &Session,
+    captures: &Captures,
+    acl: &'a config::Acl,
     access: c_int,
-) -> c_int {
-    if access == MOSQ_ACL_SUBSCRIBE {
-        return MOSQ_ERR_SUCCESS;
+    msg: Option<&mosquitto_acl_msg>,
+    legacy_subscribe: bool,
+    violations: &mut Vec<String>,
+) -> Vec<(config::Effect, Option<&'a str>)> {
+    // every operation grants the subscription with legacySubscribe
+    let legacy = access == MOSQ_ACL_SUBSCRIBE && legacy_subscribe;
+    acl.accesses
+        .iter()
+        .filter(|x| {
+            (legacy || match_access(&x.operation, access)) && match_claims(x, session, captures)
+        })
+        .filter_map(|x| {
+            let role = if x.roles.is_empty() {
+                None
+            } else {
+                Some(x.roles.iter().find(|x| session.roles.contains(x))?.as_str())
+            };
+            if access == MOSQ_ACL_WRITE {
+                let violation = x.violated_constraint(msg).or_else(|| match acl.resource {
+                    config::Resource::Dadget(_) => x.violated_transaction_rule(msg, session),
+                    _ => None,
+                });
+                if let Some(violation) = violation {
+                    violations.push(format!("{} of {}", violation, acl.name));
+                    return None;
+                }
+            }
+            Some((x.effect, role))
+        })
+        .collect()
+}
+
+impl config::Accesses {
+    /// Returns the constraint which the published message violates.
+    /// The v2 interface gives no message, which satisfies no constraint.
+    fn violated_constraint(&self, msg: Option<&mosquitto_acl_msg>) -> Option<String> {
+        if self.max_payload.is_none() && self.qos.is_none() && self.retain.is_none() {
+            return None;
+        }
+        let msg = match msg {
+            Some(x) => x,
+            None => return Some("constraints without the message".to_string()),
+        };
+        if let Some(max_payload) = self.max_payload {
+            if msg.payloadlen.max(0) as u64 > max_payload {
+                return Some(format!(
+                    "maxPayload {} ({} bytes)",
+                    max_payload, msg.payloadlen
+                ));
+            }
+        }
+        if let Some(ref qos) = self.qos {
+            if !qos.contains(&msg.qos) {
+                return Some(format!("qos {:?} (qos {})", qos, msg.qos));
+            }
+        }
+        if self.retain == Some(false) && msg.retain != 0 {
+            return Some("retain false".to_string());
+        }
+        None
     }
-    for config_access in accesses {
-        if match_access(config_access, access) && match_claims(config_access, claims) {
-            return MOSQ_ERR_SUCCESS;
+
+    /// Returns the part of the transaction rule which the published transaction violates.
+    fn violated_transaction_rule(
+        &self,
+        msg: Option<&mosquitto_acl_msg>,
+        session: &Session,
+    ) -> Option<String> {
+        let rule = self.transaction.as_ref()?;
+        let msg = match msg {
+            Some(x) if !x.payload.is_null() && x.payloadlen > 0 => x,
+            _ => return Some("transaction without the payload".to_string()),
+        };
+        let payload = unsafe {
+            std::slice::from_raw_parts(msg.payload as *const u8, msg.payloadlen as usize)
+        };
+        let transaction: Value = match serde_json::from_slice(payload) {
+            Ok(x) => x,
+            Err(e) => return Some(format!("transaction ({})", e)),
+        };
+        let transaction_type = transaction["type"].as_str().unwrap_or("");
+        if let Some(ref types) = rule.types {
+            if !types.iter().any(|x| x == transaction_type) {
+                return Some(format!(
+                    "transaction types {:?} ({})",
+                    types, transaction_type
+                ));
+            }
+        }
+        if let Some(ref document) = rule.document {
+            for key in &["before", "new"] {
+                let x = &transaction[key];
+                if !x.is_null() && !document.matches_in(x, session, &Captures::new()) {
+                    return Some(format!("transaction document ({})", key));
+                }
+            }
+            // an update carries the change in `operator` rather than `new`
+            let operator = &transaction["operator"];
+            if !operator.is_null() {
+                let updated = match apply_operator(&transaction["before"], operator) {
+                    Ok(x) => x,
+                    Err(e) => return Some(format!("transaction operator ({})", e)),
+                };
+                if !document.matches_in(&updated, session, &Captures::new()) {
+                    return Some("transaction document (operator)".to_string());
+                }
+            }
         }
+        None
     }
-    MOSQ_ERR_ACL_DENIED
 }
 
-fn match_access(config_access: &config::Accesses, access: c_int) -> bool {
-    if access == MOSQ_ACL_READ && config_access.operation.eq_ignore_ascii_case("READ") {
+/// Applies the `$set` and `$unset` of an update to the document before it.
+/// Other operators are not supported, since the rule could not be checked on the result.
+fn apply_operator(before: &Value, operator: &Value) -> Result<Value, String> {
+    let mut document = match before.as_object() {
+        Some(x) => x.clone(),
+        None => return Err("no document before the update".to_string()),
+    };
+    let operator = match operator.as_object() {
+        Some(x) => x,
+        None => return Err("not an object".to_string()),
+    };
+    for (name, fields) in operator {
+        if name != "$set" && name != "$unset" {
+            return Err(format!("unsupported {}", name));
+        }
+        let fields = match fields.as_object() {
+            Some(x) => x,
+            None => return Err(format!("{} is not an object", name)),
+        };
+        for (field, value) in fields {
+            if field.contains('.') {
+                return Err(format!("nested field {}", field));
+            }
+            if name == "$set" {
+                document.insert(field.clone(), value.clone());
+            } else {
+                document.remove(field);
+            }
+        }
+    }
+    Ok(Value::Object(document))
+}
+
+fn match_access(operation: &str, access: c_int) -> bool {
+    if access == MOSQ_ACL_READ && operation.eq_ignore_ascii_case("READ") {
         return true;
     }
-    if access == MOSQ_ACL_WRITE && config_access.operation.eq_ignore_ascii_case("WRITE") {
+    if access == MOSQ_ACL_WRITE && operation.eq_ignore_ascii_case("WRITE") {
         return true;
     }
-    if config_access.operation == "*" {
+    if access == MOSQ_ACL_SUBSCRIBE && operation.eq_ignore_ascii_case("SUBSCRIBE") {
         return true;
     }
-    return false;
+    operation == "*"
 }
 
-fn match_claims(config_access: &config::Accesses, claims: &serde_json::Value) -> bool {
+fn match_claims(config_access: &config::Accesses, session: &Session, captures: &Captures) -> bool {
     match config_access.subject {
         None => true,
-        Some(ref subject_list) => {
-            subject_list
-                .iter()
-                .all(|(key, regex)| match claims[key].as_str() {
-                    None => false,
-                    Some(x) => regex.0.is_match(x),
-                })
+        Some(ref subject) => subject.matches(session, captures),
+    }
+}
+
+impl config::Subject {
+    fn matches(&self, session: &Session, captures: &Captures) -> bool {
+        self.matches_in(&session.claims, session, captures)
+    }
+
+    /// Evaluates the conditions on the fields of `root`, which are the claims or a document.
+    fn matches_in(&self, root: &Value, session: &Session, captures: &Captures) -> bool {
+        match self {
+            config::Subject::Claims(conditions) => {
+                conditions
+                    .iter()
+                    .all(|(key, condition)| match claim(root, key) {
+                        None => {
+                            debug!("sub:{}, no field {}", session.sub(), key);
+                            false
+                        }
+                        Some(value) => match condition.check(value, session, captures) {
+                            Ok(x) => x,
+                            Err(e) => {
+                                warn!("sub:{}, field {}: {}", session.sub(), key, e);
+                                false
+                            }
+                        },
+                    })
+            }
+            config::Subject::AllOf(x) => x.iter().all(|x| x.matches_in(root, session, captures)),
+            config::Subject::AnyOf(x) => x.iter().any(|x| x.matches_in(root, session, captures)),
+            config::Subject::Not(x) => !x.matches_in(root, session, captures),
         }
     }
 }
+
+/// Returns the claim at a JSON pointer such as `/realm_access/roles`,
+/// or at a dotted path such as `realm_access.roles` unless a claim has that name.
+fn claim<'a>(claims: &'a Value, path: &str) -> Option<&'a Value> {
+    if path.starts_with('/') {
+        return claims.pointer(path);
+    }
+    match claims.get(path) {
+        Some(x) => Some(x),
+        None => path.split('.').try_fold(claims, |x, key| x.get(key)),
+    }
+}
@@ -1,66 +1,187 @@
+use chrono::{DateTime, Local};
 use config;
+use std::collections::hash_map::DefaultHasher;
+use std::error::Error;
+use std::fs;
+use std::hash::Hasher;
 use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
 use std::time::SystemTime;
 
-pub fn check_config_update(user_data: &::UserData) {
-    if {
-        let config_info = user_data.config_info.read().unwrap();
-        check_config_update_time(&user_data.config_path, &config_info)
-    } {
-        let mut config_info = user_data.config_info.write().unwrap();
-        if check_config_update_time(&user_data.config_path, &config_info) {
-            *config_info = update_config(&user_data.config_path);
-        }
-    }
-}
+static CONFIG_VERSION: AtomicUsize = AtomicUsize::new(0);
 
-pub fn no_check_config_update(user_data: &::UserData) {
-    let mut config_info = user_data.config_info.write().unwrap();
-    if check_config_update_time(&user_data.config_path, &config_info) {
-        *config_info = update_config(&user_data.config_path);
-    }
-}
+/// Change time in nanoseconds and inode of a file, following symbolic links,
+/// so that a file renamed over another one differs even in the same second.
+pub type FileStamp = (i64, u64);
 
-fn check_config_update_time(config_path: &str, config_info: &::ConfigInfo) -> bool {
-    (match config_info.last_check_time.elapsed() {
-        Ok(elapsed) => elapsed.as_secs() > ::CONFIG_FILE_CHECK_INTERVAL,
-        Err(_e) => false,
-    }) && { config_info.file_time != ctime(config_path).unwrap_or(0) }
+/// Tells whether any of the files in use has changed since it was read.
+pub fn is_config_updated(config_info: &::ConfigInfo) -> bool {
+    config_info
+        .file_times
+        .iter()
+        .any(|(path, time)| *time != stamp(path).unwrap_or_default())
 }
 
 #[cfg(unix)]
-fn ctime(file_path: &str) -> io::Result<i64> {
-    use std::fs;
+fn stamp(file_path: &str) -> io::Result<FileStamp> {
     use std::os::unix::fs::MetadataExt;
 
     let meta = fs::metadata(file_path)?;
-    Ok(meta.ctime())
+    Ok((meta.ctime() * 1_000_000_000 + meta.ctime_nsec(), meta.ino()))
 }
 
 #[cfg(windows)]
-fn ctime(file_path: &str) -> io::Result<i64> {
-    use std::fs;
+fn stamp(file_path: &str) -> io::Result<FileStamp> {
     use std::os::windows::prelude::*;
 
     let meta = fs::metadata(file_path)?;
-    Ok(meta.last_write_time() as i64)
+    Ok((meta.last_write_time() as i64, 0))
+}
+
+pub fn load_config(config_path: &str, status_file: Option<String>) -> ::ConfigInfo {
+    let mut config_info = ::ConfigInfo {
+        file_times: vec![(config_path.to_string(), FileStamp::default())],
+        version: 0,
+        hash: None,
+        last_failure: None,
+        status_file,
+        config: None,
+    };
+    update_config(config_path, &mut config_info, read_config(config_path));
+    config_info
+}
+
+/// Reloads the config files. They are read before the lock is taken,
+/// so that the checks go on with the previous policy meanwhile.
+pub fn reload_config(config_path: &str, config_info: &RwLock<::ConfigInfo>) {
+    let loaded = read_config(config_path);
+    let mut config_info = config_info.write().unwrap();
+    update_config(config_path, &mut config_info, loaded);
+}
+
+/// Config files which have been read, before they are put in use
+struct Loaded {
+    config: config::Config,
+    file_times: Vec<(String, FileStamp)>,
+    hash: String,
+}
+
+/// Config files which could not be put in use
+struct Failed {
+    error: Box<dyn Error>,
+    /// files which the config file names, when it has been read
+    file_times: Option<Vec<(String, FileStamp)>>,
+}
+
+impl<E: Into<Box<dyn Error>>> From<E> for Failed {
+    fn from(error: E) -> Failed {
+        Failed {
+            error: error.into(),
+            file_times: None,
+        }
+    }
+}
+
+fn read_config(config_path: &str) -> Result<Loaded, Failed> {
+    // the stamps are taken first, so that a change while reading is not missed
+    let config_stamp = stamp(config_path).unwrap_or_default();
+    let mut config = config::parse_file(config_path)?;
+
+    // the linked files are reloaded together with the config file
+    let mut file_times = vec![(config_path.to_string(), config_stamp)];
+    for path in config.linked_files() {
+        file_times.push((path.to_string(), stamp(path).unwrap_or_default()));
+    }
+    // a linked file which is missing or broken is watched until it is fixed
+    if let Err(error) = config.load_linked_files() {
+        return Err(Failed {
+            error,
+            file_times: Some(file_times),
+        });
+    }
+    for warning in config.warnings() {
+        warn!("{}: {}", warning, config_path);
+    }
+    debug!("config {:?}", config);
+    let paths: Vec<_> = file_times.iter().map(|(x, _)| x.as_str()).collect();
+    let hash = hash_files(&paths);
+    Ok(Loaded {
+        config,
+        file_times,
+        hash,
+    })
 }
 
-pub fn update_config(config_path: &str) -> ::ConfigInfo {
-    // load a config file
-    let config = match config::read_from_file(config_path) {
-        Ok(x) => Some(Box::new(x)),
+/// Replaces the policy only when the config files are valid.
+/// Otherwise the previous policy is kept until the files are changed again.
+fn update_config(
+    config_path: &str,
+    config_info: &mut ::ConfigInfo,
+    loaded: Result<Loaded, Failed>,
+) {
+    let loaded = match loaded {
+        Ok(x) => x,
         Err(e) => {
-            error!("{}: {}", e, config_path);
-            None
+            error!(
+                "{}: {}, keeping the config version {}",
+                e.error, config_path, config_info.version
+            );
+            config_info.last_failure = Some(SystemTime::now());
+            match e.file_times {
+                Some(x) => config_info.file_times = x,
+                None => {
+                    for (path, time) in config_info.file_times.iter_mut() {
+                        *time = stamp(path).unwrap_or_default();
+                    }
+                }
+            }
+            write_status(config_info);
+            return;
         }
     };
-    debug!("config {:?}", config);
-    let file_time = ctime(config_path).unwrap_or(0);
+    config_info.file_times = loaded.file_times;
+    config_info.hash = Some(loaded.hash);
+    config_info.version = CONFIG_VERSION.fetch_add(1, Ordering::SeqCst) + 1;
+    info!(
+        "config version {} ({}): {}",
+        config_info.version,
+        config_info.hash.as_ref().unwrap(),
+        config_path
+    );
+    config_info.config = Some(Box::new(loaded.config));
+    write_status(config_info);
+}
 
-    ::ConfigInfo {
-        last_check_time: SystemTime::now(),
-        file_time,
-        config,
+/// Writes the status of the policy in use, where an operator or a probe can read it.
+/// The file is replaced by a rename, so that it is never read half written.
+fn write_status(config_info: &::ConfigInfo) {
+    let path = match config_info.status_file {
+        Some(ref x) => x,
+        None => return,
+    };
+    let time = |x: SystemTime| {
+        DateTime::<Local>::from(x)
+            .format(::LOG_DATE_FORMAT)
+            .to_string()
+    };
+    let status = json!({
+        "version": config_info.version,
+        "hash": config_info.hash,
+        "lastFailure": config_info.last_failure.map(time),
+    });
+    let tmp = format!("{}.tmp", path);
+    if let Err(e) = fs::write(&tmp, status.to_string()).and_then(|_| fs::rename(&tmp, path)) {
+        warn!("cannot write the config status: {}: {}", e, path);
+    }
+}
+
+/// Identifies the contents of the files in use.
+fn hash_files(paths: &[&str]) -> String {
+    let mut hasher = DefaultHasher::new();
+    for path in paths {
+        hasher.write(path.as_bytes());
+        hasher.write(&fs::read(path).unwrap_or_default());
     }
+    format!("{:016x}", hasher.finish())
 }
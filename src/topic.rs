@@ -0,0 +1,54 @@
+//! MQTT topic filters
+//!
+//! Filters are matched level by level. As in the MQTT specification, a wildcard
+//! at the first level does not match a topic beginning with `$`.
+
+/// Returns an error when the filter has a misplaced wildcard.
+pub fn validate_filter(filter: &str) -> Result<(), String> {
+    let mut levels = filter.split('/').peekable();
+    while let Some(level) = levels.next() {
+        if level.contains('#') && (level != "#" || levels.peek().is_some()) {
+            return Err(format!("'#' must be the last level: {}", filter));
+        }
+        if level.contains('+') && level != "+" {
+            return Err(format!("'+' must occupy an entire level: {}", filter));
+        }
+    }
+    Ok(())
+}
+
+pub fn has_wildcard(filter: &str) -> bool {
+    filter.split('/').any(|x| x == "+" || x == "#")
+}
+
+/// Returns true when every topic matched by `requested` is also matched by `granted`.
+/// A topic is a filter without wildcards, so this also tests whether `granted` matches a topic.
+pub fn contains(granted: &str, requested: &str) -> bool {
+    let mut granted = granted.split('/');
+    let mut requested = requested.split('/');
+    let mut first = true;
+    loop {
+        match (granted.next(), requested.next()) {
+            // `#` also matches the parent level
+            (Some("#"), x) => return !(first && x.is_some_and(|x| x.starts_with('$'))),
+            (Some("+"), Some(x)) => {
+                if x == "#" || (first && x.starts_with('$')) {
+                    return false;
+                }
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return false;
+                }
+            }
+            (None, None) => return true,
+            _ => return false,
+        }
+        first = false;
+    }
+}
+
+/// Returns true when `x` is a single level without wildcards.
+pub fn is_level(x: &str) -> bool {
+    !x.is_empty() && !x.contains(['/', '+', '#', '\0'])
+}
@@ -0,0 +1,194 @@
+#[cfg(target_os = "linux")]
+use inotify::{Inotify, WatchDescriptor, WatchMask};
+use misc;
+#[cfg(target_os = "linux")]
+use std::collections::{HashMap, HashSet};
+#[cfg(target_os = "linux")]
+use std::fs;
+#[cfg(target_os = "linux")]
+use std::io;
+#[cfg(target_os = "linux")]
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Period to read the events and to check for the end of the thread
+const TICK: Duration = Duration::from_millis(100);
+/// Quiet period after the last event, since an editor saves a file in several steps
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Starts a thread which reloads the config files when they change, until `stop` is disconnected.
+/// The files are also polled every `poll_interval`, which is the only way
+/// to notice a change where inotify is unavailable.
+pub fn watch_config(
+    config_path: String,
+    config_info: Arc<RwLock<::ConfigInfo>>,
+    poll_interval: Option<Duration>,
+    stop: Receiver<()>,
+) -> thread::JoinHandle<()> {
+    // the files are watched before this returns, and a change since they were loaded
+    // is caught up with, so that no change is left until the next poll
+    let mut events = Events::new();
+    events.rearm(&watched_files(&config_info));
+    let updated = misc::is_config_updated(&config_info.read().unwrap());
+    if updated {
+        misc::reload_config(&config_path, &config_info);
+        events.rearm(&watched_files(&config_info));
+    }
+    thread::spawn(move || run(events, config_path, config_info, poll_interval, stop))
+}
+
+fn run(
+    mut events: Events,
+    config_path: String,
+    config_info: Arc<RwLock<::ConfigInfo>>,
+    poll_interval: Option<Duration>,
+    stop: Receiver<()>,
+) {
+    debug!("start a watch thread");
+    let mut last_poll = Instant::now();
+    let mut last_event: Option<Instant> = None;
+    while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(TICK) {
+        if events.read() {
+            last_event = Some(Instant::now());
+        }
+        let settled = last_event.is_some_and(|x| x.elapsed() >= DEBOUNCE);
+        let polled = poll_interval.is_some_and(|x| last_poll.elapsed() >= x);
+        if !settled && !polled {
+            continue;
+        }
+        last_event = None;
+        last_poll = Instant::now();
+        let updated = misc::is_config_updated(&config_info.read().unwrap());
+        if updated {
+            misc::reload_config(&config_path, &config_info);
+        }
+        // the linked files and the targets of the links may have changed
+        events.rearm(&watched_files(&config_info));
+    }
+    debug!("stop a watch thread");
+}
+
+fn watched_files(config_info: &RwLock<::ConfigInfo>) -> Vec<String> {
+    let config_info = config_info.read().unwrap();
+    config_info
+        .file_times
+        .iter()
+        .map(|(x, _)| x.to_string())
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+struct Events {
+    inotify: Option<Inotify>,
+    watches: HashMap<PathBuf, WatchDescriptor>,
+    buffer: [u8; 4096],
+}
+
+#[cfg(target_os = "linux")]
+impl Events {
+    fn new() -> Events {
+        let inotify = match Inotify::init() {
+            Ok(x) => Some(x),
+            Err(e) => {
+                warn!("inotify is unavailable, the config files are polled: {}", e);
+                None
+            }
+        };
+        Events {
+            inotify,
+            watches: HashMap::new(),
+            buffer: [0; 4096],
+        }
+    }
+
+    /// Watches the directories of the files rather than the files,
+    /// so that a file renamed over another one is noticed.
+    /// The directories of the targets of symbolic links are watched too,
+    /// as a ConfigMap of Kubernetes swaps a link to a directory.
+    fn rearm(&mut self, files: &[String]) {
+        let inotify = match self.inotify {
+            Some(ref mut x) => x,
+            None => return,
+        };
+        let parent = |x: &Path| match x.parent() {
+            Some(x) if !x.as_os_str().is_empty() => x.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let mut dirs = HashSet::new();
+        for file in files {
+            let file = Path::new(file);
+            dirs.insert(parent(file));
+            if let Ok(target) = fs::canonicalize(file) {
+                dirs.insert(parent(&target));
+            }
+        }
+        self.watches.retain(|dir, wd| {
+            dirs.contains(dir) || {
+                // the watch is gone if the directory has been removed
+                let _ = inotify.watches().remove(wd.clone());
+                false
+            }
+        });
+        let mask = WatchMask::CLOSE_WRITE
+            | WatchMask::MODIFY
+            | WatchMask::ATTRIB
+            | WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::MOVED_TO;
+        for dir in dirs {
+            if self.watches.contains_key(&dir) {
+                continue;
+            }
+            match inotify.watches().add(&dir, mask) {
+                Ok(wd) => {
+                    debug!("watch {}", dir.display());
+                    self.watches.insert(dir, wd);
+                }
+                Err(e) => warn!("cannot watch {}: {}", dir.display(), e),
+            }
+        }
+    }
+
+    /// Tells whether any event has come since the last call.
+    fn read(&mut self) -> bool {
+        let inotify = match self.inotify {
+            Some(ref mut x) => x,
+            None => return false,
+        };
+        let mut any = false;
+        loop {
+            match inotify.read_events(&mut self.buffer) {
+                Ok(mut x) => {
+                    if x.next().is_none() {
+                        return any;
+                    }
+                    any = true;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return any,
+                Err(e) => {
+                    warn!("inotify: {}", e);
+                    return any;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+struct Events;
+
+#[cfg(not(target_os = "linux"))]
+impl Events {
+    fn new() -> Events {
+        Events
+    }
+
+    fn rearm(&mut self, _files: &[String]) {}
+
+    fn read(&mut self) -> bool {
+        false
+    }
+}
@@ -1,12 +1,16 @@
 extern crate chipin_mqtt_auth_plugin;
 extern crate jsonwebtoken;
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
 
 use chipin_mqtt_auth_plugin::*;
-use jsonwebtoken::{encode, Header};
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
 use std::ffi::CString;
 use std::os::raw::c_int;
+use std::path::Path;
 use std::time::{Instant, SystemTime, UNIX_EPOCH};
 #[derive(Debug, Serialize, Deserialize)]
 struct Claims {
@@ -23,7 +27,7 @@ fn unix_time() -> u64 {
 }
 #[test]
 fn test_proc_mosquitto_auth_plugin_init() {
-    let user_data: Box<*mut UserData> = Box::new(0 as *mut UserData);
+    let user_data: Box<*mut UserData> = Box::new(std::ptr::null_mut());
     let ptr_user_data = Box::into_raw(user_data);
 
     let mut acl_file = std::env::current_dir().unwrap();
@@ -196,7 +200,7 @@ fn test_proc_mosquitto_auth_plugin_init() {
 
 #[test]
 fn test_acl2() {
-    let user_data: Box<*mut UserData> = Box::new(0 as *mut UserData);
+    let user_data: Box<*mut UserData> = Box::new(std::ptr::null_mut());
     let ptr_user_data = Box::into_raw(user_data);
 
     let mut acl_file = std::env::current_dir().unwrap();
@@ -256,8 +260,7 @@ fn test_acl2() {
 }
 
 fn check1(ptr_user_data: *mut *mut UserData, topic: &str, access: c_int, claims: &Claims) -> c_int {
-    let token = encode(&Header::default(), &claims, "q6r2MewgJmLc".as_ref()).unwrap();
-    let token = CString::new(token).expect("error");
+    let token = CString::new(token(claims)).expect("error");
 
     ::proc_mosquitto_auth_unpwd_check_v2(unsafe { *ptr_user_data }, token.as_ptr(), ::NULL);
 
@@ -275,3 +278,1498 @@ fn check1(ptr_user_data: *mut *mut UserData, topic: &str, access: c_int, claims:
     println!("{} nano sec", end.subsec_nanos());
     result
 }
+
+fn init_plugin(file_name: &str) -> *mut *mut UserData {
+    let mut acl_file = std::env::current_dir().unwrap();
+    acl_file.push("samples");
+    acl_file.push(file_name);
+    init_plugin_at(&acl_file, None)
+}
+
+fn init_plugin_at(acl_file: &Path, status_file: Option<&Path>) -> *mut *mut UserData {
+    let user_data: Box<*mut UserData> = Box::new(std::ptr::null_mut());
+    let ptr_user_data = Box::into_raw(user_data);
+
+    let mut opts = vec![(::DEFAULT_CONFIG_PATH_OPT_KEY, acl_file)];
+    if let Some(x) = status_file {
+        opts.push((::DEFAULT_CONFIG_STATUS_FILE_OPT_KEY, x));
+    }
+    let opts: Vec<_> = opts
+        .into_iter()
+        .map(|(key, value)| {
+            let key = CString::new(key).unwrap();
+            let value = CString::new(value.to_str().unwrap()).unwrap();
+            (key, value)
+        })
+        .collect();
+    let mosquitto_opt: Vec<_> = opts
+        .iter()
+        .map(|(key, value)| ::mosquitto_opt {
+            key: key.as_ptr(),
+            value: value.as_ptr(),
+        })
+        .collect();
+    ::proc_mosquitto_auth_plugin_init(ptr_user_data, &mosquitto_opt[0], mosquitto_opt.len() as i32);
+    ptr_user_data
+}
+
+/// Reads the status file of the config, with the version as a number.
+fn config_status(status_file: &Path) -> (u64, serde_json::Value) {
+    let status: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(status_file).unwrap()).unwrap();
+    (status["version"].as_u64().unwrap(), status)
+}
+
+fn cleanup_plugin(ptr_user_data: *mut *mut UserData) {
+    ::proc_mosquitto_auth_plugin_cleanup(unsafe { *ptr_user_data }, std::ptr::null(), 0);
+    unsafe { drop(Box::from_raw(ptr_user_data)) }
+}
+
+fn auth(ptr_user_data: *mut *mut UserData, token: &str) -> c_int {
+    let token = CString::new(token).expect("error");
+    ::proc_mosquitto_auth_unpwd_check_v2(unsafe { *ptr_user_data }, token.as_ptr(), ::NULL)
+}
+
+#[test]
+fn test_asymmetric_keys() {
+    let ptr_user_data = init_plugin("acl3.json");
+    let claims = Claims {
+        sub: "xxxx@example.jp",
+        xattr: "33333",
+        exp: unix_time() + 10,
+    };
+
+    let rsa_key =
+        EncodingKey::from_rsa_pem(include_bytes!("../samples/keys/rsa_private.pem")).unwrap();
+    let ec_key =
+        EncodingKey::from_ec_pem(include_bytes!("../samples/keys/ec_private.pem")).unwrap();
+    let ed_key =
+        EncodingKey::from_ed_pem(include_bytes!("../samples/keys/ed_private.pem")).unwrap();
+
+    let token = encode(&Header::new(Algorithm::RS256), &claims, &rsa_key).unwrap();
+    assert_eq!(auth(ptr_user_data, &token), ::MOSQ_ERR_SUCCESS);
+    let token = encode(&Header::new(Algorithm::ES256), &claims, &ec_key).unwrap();
+    assert_eq!(auth(ptr_user_data, &token), ::MOSQ_ERR_SUCCESS);
+    let token = encode(&Header::new(Algorithm::EdDSA), &claims, &ed_key).unwrap();
+    assert_eq!(auth(ptr_user_data, &token), ::MOSQ_ERR_SUCCESS);
+
+    // RS384 is not in the allowlist even though an RSA key is configured
+    let token = encode(&Header::new(Algorithm::RS384), &claims, &rsa_key).unwrap();
+    assert_eq!(auth(ptr_user_data, &token), ::MOSQ_ERR_AUTH);
+
+    // algorithm confusion: HS256 signed with the public key as the secret
+    let public_key = include_bytes!("../samples/keys/rsa_public.pem");
+    let token = encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &EncodingKey::from_secret(public_key),
+    )
+    .unwrap();
+    assert_eq!(auth(ptr_user_data, &token), ::MOSQ_ERR_AUTH);
+
+    // alg: none
+    let token = encode(&Header::new(Algorithm::RS256), &claims, &rsa_key).unwrap();
+    let parts: Vec<&str> = token.split('.').collect();
+    let token = format!("eyJhbGciOiJub25lIiwidHlwIjoiSldUIn0.{}.", parts[1]);
+    assert_eq!(auth(ptr_user_data, &token), ::MOSQ_ERR_AUTH);
+
+    // ES256 token whose payload was replaced after signing
+    let token = encode(&Header::new(Algorithm::ES256), &claims, &ec_key).unwrap();
+    let other = encode(
+        &Header::new(Algorithm::ES256),
+        &Claims {
+            sub: "yyyy@example.jp",
+            xattr: "33333",
+            exp: unix_time() + 10,
+        },
+        &ec_key,
+    )
+    .unwrap();
+    let parts: Vec<&str> = token.split('.').collect();
+    let other_parts: Vec<&str> = other.split('.').collect();
+    let token = format!("{}.{}.{}", parts[0], other_parts[1], parts[2]);
+    assert_eq!(auth(ptr_user_data, &token), ::MOSQ_ERR_AUTH);
+
+    cleanup_plugin(ptr_user_data);
+}
+
+#[test]
+fn test_jwks() {
+    let ptr_user_data = init_plugin("acl4.json");
+    let claims = Claims {
+        sub: "xxxx@example.jp",
+        xattr: "33333",
+        exp: unix_time() + 10,
+    };
+    let old_key =
+        EncodingKey::from_rsa_pem(include_bytes!("../samples/keys/rsa_private.pem")).unwrap();
+    let new_key =
+        EncodingKey::from_rsa_pem(include_bytes!("../samples/keys/rsa2_private.pem")).unwrap();
+    let header = |kid: Option<&str>| {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = kid.map(|x| x.to_string());
+        header
+    };
+
+    // both keys are valid during the rotation window
+    let token = encode(&header(Some("rsa-old")), &claims, &old_key).unwrap();
+    assert_eq!(auth(ptr_user_data, &token), ::MOSQ_ERR_SUCCESS);
+    let token = encode(&header(Some("rsa-new")), &claims, &new_key).unwrap();
+    assert_eq!(auth(ptr_user_data, &token), ::MOSQ_ERR_SUCCESS);
+
+    // without kid, every key is tried
+    let token = encode(&header(None), &claims, &old_key).unwrap();
+    assert_eq!(auth(ptr_user_data, &token), ::MOSQ_ERR_SUCCESS);
+    let token = encode(&header(None), &claims, &new_key).unwrap();
+    assert_eq!(auth(ptr_user_data, &token), ::MOSQ_ERR_SUCCESS);
+
+    // kid selects the key
+    let token = encode(&header(Some("rsa-old")), &claims, &new_key).unwrap();
+    assert_eq!(auth(ptr_user_data, &token), ::MOSQ_ERR_AUTH);
+    let token = encode(&header(Some("unknown")), &claims, &new_key).unwrap();
+    assert_eq!(auth(ptr_user_data, &token), ::MOSQ_ERR_AUTH);
+
+    cleanup_plugin(ptr_user_data);
+}
+
+#[test]
+fn test_legacy_key_with_kid() {
+    // the legacy key has no kid, so that it verifies a token with any kid
+    let ptr_user_data = init_plugin("acl.json");
+    let claims = Claims {
+        sub: "xxxx@example.jp",
+        xattr: "33333",
+        exp: unix_time() + 10,
+    };
+    let header = Header {
+        kid: Some("k1".to_string()),
+        ..Header::default()
+    };
+    let token = encode(&header, &claims, &EncodingKey::from_secret(SECRET.as_ref())).unwrap();
+    assert_eq!(auth(ptr_user_data, &token), ::MOSQ_ERR_SUCCESS);
+    let token = encode(
+        &header,
+        &claims,
+        &EncodingKey::from_secret("other".as_ref()),
+    )
+    .unwrap();
+    assert_eq!(auth(ptr_user_data, &token), ::MOSQ_ERR_AUTH);
+
+    cleanup_plugin(ptr_user_data);
+}
+
+#[test]
+fn test_claim_validation() {
+    let ptr_user_data = init_plugin("acl5.json");
+    let now = unix_time();
+
+    let valid = json!({
+        "sub": "xxxx@example.jp",
+        "iss": "https://idp.example.jp",
+        "aud": "mqtt-broker",
+        "iat": now,
+        "exp": now + 600,
+    });
+    assert_eq!(auth(ptr_user_data, &token(&valid)), ::MOSQ_ERR_SUCCESS);
+
+    // a token minted for another service
+    let mut claims = valid.clone();
+    claims["aud"] = json!(["rest-api"]);
+    assert_eq!(auth(ptr_user_data, &token(&claims)), ::MOSQ_ERR_AUTH);
+    let mut claims = valid.clone();
+    claims.as_object_mut().unwrap().remove("aud");
+    assert_eq!(auth(ptr_user_data, &token(&claims)), ::MOSQ_ERR_AUTH);
+
+    let mut claims = valid.clone();
+    claims["iss"] = json!("https://other.example.jp");
+    assert_eq!(auth(ptr_user_data, &token(&claims)), ::MOSQ_ERR_AUTH);
+
+    // nbf and exp with leeway
+    let mut claims = valid.clone();
+    claims["nbf"] = json!(now + 10);
+    assert_eq!(auth(ptr_user_data, &token(&claims)), ::MOSQ_ERR_SUCCESS);
+    let mut claims = valid.clone();
+    claims["nbf"] = json!(now + 120);
+    assert_eq!(auth(ptr_user_data, &token(&claims)), ::MOSQ_ERR_AUTH);
+    let mut claims = valid.clone();
+    claims["exp"] = json!(now - 10);
+    assert_eq!(auth(ptr_user_data, &token(&claims)), ::MOSQ_ERR_SUCCESS);
+    let mut claims = valid.clone();
+    claims["exp"] = json!(now - 120);
+    assert_eq!(auth(ptr_user_data, &token(&claims)), ::MOSQ_ERR_AUTH);
+
+    // maximum lifetime
+    let mut claims = valid.clone();
+    claims["exp"] = json!(now + 7200);
+    assert_eq!(auth(ptr_user_data, &token(&claims)), ::MOSQ_ERR_AUTH);
+    let mut claims = valid.clone();
+    claims.as_object_mut().unwrap().remove("iat");
+    assert_eq!(auth(ptr_user_data, &token(&claims)), ::MOSQ_ERR_AUTH);
+
+    cleanup_plugin(ptr_user_data);
+}
+
+fn auth_v3(ptr_user_data: *mut *mut UserData, client: *const mosquitto, token: &str) -> c_int {
+    let token = CString::new(token).expect("error");
+    ::proc_mosquitto_auth_unpwd_check_v3(
+        unsafe { *ptr_user_data },
+        client,
+        ::NULL,
+        token.as_ptr(),
+        ::NULL,
+    )
+}
+
+fn acl_v3(
+    ptr_user_data: *mut *mut UserData,
+    client: *const mosquitto,
+    topic: &str,
+    access: c_int,
+) -> c_int {
+    let topic = CString::new(topic).expect("error");
+    let msg = ::mosquitto_acl_msg {
+        topic: topic.as_ptr(),
+        payload: std::ptr::null(),
+        payloadlen: 0,
+        qos: 0,
+        retain: 0,
+    };
+    ::proc_mosquitto_auth_acl_check_v3(unsafe { *ptr_user_data }, access, client, &msg)
+}
+
+fn publish_v3(
+    ptr_user_data: *mut *mut UserData,
+    client: *const mosquitto,
+    topic: &str,
+    payload: &[u8],
+    qos: c_int,
+    retain: bool,
+) -> c_int {
+    let topic = CString::new(topic).expect("error");
+    let msg = ::mosquitto_acl_msg {
+        topic: topic.as_ptr(),
+        payload: payload.as_ptr() as *const _,
+        payloadlen: payload.len() as _,
+        qos,
+        retain: retain as _,
+    };
+    ::proc_mosquitto_auth_acl_check_v3(unsafe { *ptr_user_data }, ::MOSQ_ACL_WRITE, client, &msg)
+}
+
+/// Secret of the `key` in the samples
+const SECRET: &str = "q6r2MewgJmLc";
+
+/// Signs the claims with the secret of the samples.
+fn token<T: serde::Serialize>(claims: &T) -> String {
+    encode(
+        &Header::default(),
+        claims,
+        &EncodingKey::from_secret(SECRET.as_ref()),
+    )
+    .unwrap()
+}
+
+/// Handle of the i-th client, which the plugin only uses as the key of its session.
+fn client(i: usize) -> *const mosquitto {
+    (i + 1) as *const mosquitto
+}
+
+/// Authenticates a client with a token of the claims.
+fn login(
+    ptr_user_data: *mut *mut UserData,
+    client: *const mosquitto,
+    claims: serde_json::Value,
+) -> c_int {
+    auth_v3(ptr_user_data, client, &token(&claims))
+}
+
+#[test]
+fn test_session() {
+    let ptr_user_data = init_plugin("acl.json");
+    let (client1, client2) = (client(0), client(1));
+    let claims = |sub, exp| json!({"sub": sub, "xattr": "33333", "exp": exp});
+
+    assert_eq!(
+        login(
+            ptr_user_data,
+            client1,
+            claims("xxxx@example.jp", unix_time() + 10)
+        ),
+        ::MOSQ_ERR_SUCCESS
+    );
+    assert_eq!(
+        acl_v3(ptr_user_data, client1, "/mqtt_test2", ::MOSQ_ACL_WRITE),
+        ::MOSQ_ERR_SUCCESS
+    );
+    // the claims of client1 are not shared with client2
+    assert_eq!(
+        acl_v3(ptr_user_data, client2, "/mqtt_test2", ::MOSQ_ACL_READ),
+        ::MOSQ_ERR_ACL_DENIED
+    );
+
+    // a failed authentication drops the previous session
+    assert_eq!(
+        login(
+            ptr_user_data,
+            client2,
+            claims("aaa@example.jp", unix_time() + 10)
+        ),
+        ::MOSQ_ERR_SUCCESS
+    );
+    assert_eq!(
+        acl_v3(ptr_user_data, client2, "/mqtt_test2", ::MOSQ_ACL_READ),
+        ::MOSQ_ERR_SUCCESS
+    );
+    assert_eq!(
+        acl_v3(ptr_user_data, client2, "/mqtt_test2", ::MOSQ_ACL_WRITE),
+        ::MOSQ_ERR_ACL_DENIED
+    );
+    // a failed authentication drops the previous session
+    assert_eq!(auth_v3(ptr_user_data, client2, "illegal"), ::MOSQ_ERR_AUTH);
+    assert_eq!(
+        acl_v3(ptr_user_data, client2, "/mqtt_test2", ::MOSQ_ACL_READ),
+        ::MOSQ_ERR_ACL_DENIED
+    );
+
+    // the cached claims expire with the token
+    assert_eq!(
+        login(
+            ptr_user_data,
+            client1,
+            claims("xxxx@example.jp", unix_time() + 1)
+        ),
+        ::MOSQ_ERR_SUCCESS
+    );
+    std::thread::sleep(std::time::Duration::from_secs(2));
+    assert_eq!(
+        acl_v3(ptr_user_data, client1, "/mqtt_test2", ::MOSQ_ACL_READ),
+        ::MOSQ_ERR_ACL_DENIED
+    );
+
+    cleanup_plugin(ptr_user_data);
+}
+
+#[test]
+fn test_session_expiry() {
+    let disconnect = init_plugin("acl6.json");
+    let grace = init_plugin("acl7.json");
+    let client = client(0);
+    let token = token(&Claims {
+        sub: "xxxx@example.jp",
+        xattr: "33333",
+        exp: unix_time() + 1,
+    });
+
+    for ptr_user_data in &[disconnect, grace] {
+        assert_eq!(auth_v3(*ptr_user_data, client, &token), ::MOSQ_ERR_SUCCESS);
+        assert_eq!(auth(*ptr_user_data, &token), ::MOSQ_ERR_SUCCESS);
+    }
+    std::thread::sleep(std::time::Duration::from_secs(2));
+
+    assert_eq!(
+        acl_v3(disconnect, client, "/mqtt_test", ::MOSQ_ACL_READ),
+        ::CHIPIN_ERR_SESSION_EXPIRED
+    );
+    let token_str = CString::new(token.clone()).unwrap();
+    let topic = CString::new("/mqtt_test").unwrap();
+    assert_eq!(
+        ::proc_mosquitto_auth_acl_check_v2(
+            unsafe { *disconnect },
+            ::NULL,
+            token_str.as_ptr(),
+            topic.as_ptr(),
+            ::MOSQ_ACL_READ,
+        ),
+        ::MOSQ_ERR_ACL_DENIED
+    );
+
+    // the session outlives the token during the grace period
+    assert_eq!(
+        acl_v3(grace, client, "/mqtt_test", ::MOSQ_ACL_READ),
+        ::MOSQ_ERR_SUCCESS
+    );
+    // but a new connection needs a valid token
+    assert_eq!(auth_v3(grace, client, &token), ::MOSQ_ERR_AUTH);
+
+    cleanup_plugin(disconnect);
+    cleanup_plugin(grace);
+}
+
+#[test]
+fn test_revocation() {
+    let ptr_user_data = init_plugin("acl8.json");
+    let now = unix_time();
+
+    let valid = json!({
+        "sub": "xxxx@example.jp",
+        "jti": "0f8e6c1a-valid",
+        "iat": now,
+        "exp": now + 600,
+    });
+    assert_eq!(auth(ptr_user_data, &token(&valid)), ::MOSQ_ERR_SUCCESS);
+
+    let mut claims = valid.clone();
+    claims["jti"] = json!("0f8e6c1a-revoked");
+    let revoked = token(&claims);
+    assert_eq!(auth(ptr_user_data, &revoked), ::MOSQ_ERR_AUTH);
+    // also checked on the ACL path
+    let revoked = CString::new(revoked).unwrap();
+    let topic = CString::new("/mqtt_test").unwrap();
+    assert_eq!(
+        ::proc_mosquitto_auth_acl_check_v2(
+            unsafe { *ptr_user_data },
+            ::NULL,
+            revoked.as_ptr(),
+            topic.as_ptr(),
+            ::MOSQ_ACL_READ,
+        ),
+        ::MOSQ_ERR_ACL_DENIED
+    );
+
+    let mut claims = valid.clone();
+    claims["sub"] = json!("stolen@example.jp");
+    assert_eq!(auth(ptr_user_data, &token(&claims)), ::MOSQ_ERR_AUTH);
+
+    // tokens issued before the revocation time
+    let mut claims = valid.clone();
+    claims["sub"] = json!("yyyy@example.jp");
+    assert_eq!(auth(ptr_user_data, &token(&claims)), ::MOSQ_ERR_SUCCESS);
+    claims["iat"] = json!(1600000000);
+    assert_eq!(auth(ptr_user_data, &token(&claims)), ::MOSQ_ERR_AUTH);
+    claims.as_object_mut().unwrap().remove("iat");
+    assert_eq!(auth(ptr_user_data, &token(&claims)), ::MOSQ_ERR_AUTH);
+
+    cleanup_plugin(ptr_user_data);
+}
+
+#[test]
+fn test_token_source() {
+    let ptr_user_data = init_plugin("acl9.json");
+    let client = client(0);
+    let token = token(&json!({
+        "sub": "xxxx@example.jp",
+        "device_id": "dev1",
+        "exp": unix_time() + 10,
+    }));
+    let token = CString::new(token).unwrap();
+    let dev1 = CString::new("dev1").unwrap();
+    let dev2 = CString::new("dev2").unwrap();
+    let topic = CString::new("/mqtt_test").unwrap();
+    let user_data = unsafe { *ptr_user_data };
+
+    assert_eq!(
+        ::proc_mosquitto_auth_unpwd_check_v3(
+            user_data,
+            client,
+            ::NULL,
+            dev1.as_ptr(),
+            token.as_ptr()
+        ),
+        ::MOSQ_ERR_SUCCESS
+    );
+    assert_eq!(
+        acl_v3(ptr_user_data, client, "/mqtt_test", ::MOSQ_ACL_WRITE),
+        ::MOSQ_ERR_SUCCESS
+    );
+    // the username has to match the claim
+    assert_eq!(
+        ::proc_mosquitto_auth_unpwd_check_v3(
+            user_data,
+            client,
+            ::NULL,
+            dev2.as_ptr(),
+            token.as_ptr()
+        ),
+        ::MOSQ_ERR_AUTH
+    );
+    assert_eq!(
+        ::proc_mosquitto_auth_unpwd_check_v3(user_data, client, ::NULL, ::NULL, token.as_ptr()),
+        ::MOSQ_ERR_AUTH
+    );
+    let no_claim = ::token(&json!({
+        "sub": "xxxx@example.jp",
+        "exp": unix_time() + 10,
+    }));
+    let no_claim = CString::new(no_claim).unwrap();
+    assert_eq!(
+        ::proc_mosquitto_auth_unpwd_check_v3(user_data, client, ::NULL, ::NULL, no_claim.as_ptr()),
+        ::MOSQ_ERR_AUTH
+    );
+    // the token is not read from the username
+    assert_eq!(
+        ::proc_mosquitto_auth_unpwd_check_v3(
+            user_data,
+            client,
+            ::NULL,
+            token.as_ptr(),
+            dev1.as_ptr()
+        ),
+        ::MOSQ_ERR_AUTH
+    );
+
+    // the v2 interface keeps the session by username
+    assert_eq!(
+        ::proc_mosquitto_auth_unpwd_check_v2(user_data, dev1.as_ptr(), token.as_ptr()),
+        ::MOSQ_ERR_SUCCESS
+    );
+    assert_eq!(
+        ::proc_mosquitto_auth_acl_check_v2(
+            user_data,
+            ::NULL,
+            dev1.as_ptr(),
+            topic.as_ptr(),
+            ::MOSQ_ACL_WRITE,
+        ),
+        ::MOSQ_ERR_SUCCESS
+    );
+    assert_eq!(
+        ::proc_mosquitto_auth_acl_check_v2(
+            user_data,
+            ::NULL,
+            dev2.as_ptr(),
+            topic.as_ptr(),
+            ::MOSQ_ACL_WRITE,
+        ),
+        ::MOSQ_ERR_ACL_DENIED
+    );
+
+    cleanup_plugin(ptr_user_data);
+}
+
+#[test]
+fn test_v2_sessions() {
+    let unbound = init_plugin("acl30.json");
+    let grace = init_plugin("acl31.json");
+    let password = |device_id: &str, exp: u64| {
+        let claims = json!({"sub": "xxxx@example.jp", "device_id": device_id, "exp": exp});
+        CString::new(token(&claims)).unwrap()
+    };
+    let dev1 = CString::new("dev1").unwrap();
+    let dev2 = CString::new("dev2").unwrap();
+    let topic = CString::new("/mqtt_test").unwrap();
+
+    // without usernameClaim, the username is not authenticated by the token in the password
+    assert_eq!(
+        ::proc_mosquitto_auth_unpwd_check_v2(
+            unsafe { *unbound },
+            dev1.as_ptr(),
+            password("dev1", unix_time() + 10).as_ptr()
+        ),
+        ::MOSQ_ERR_AUTH
+    );
+
+    // another login keeps the sessions in the grace period
+    assert_eq!(
+        ::proc_mosquitto_auth_unpwd_check_v2(
+            unsafe { *grace },
+            dev1.as_ptr(),
+            password("dev1", unix_time() + 1).as_ptr()
+        ),
+        ::MOSQ_ERR_SUCCESS
+    );
+    std::thread::sleep(std::time::Duration::from_secs(2));
+    assert_eq!(
+        ::proc_mosquitto_auth_unpwd_check_v2(
+            unsafe { *grace },
+            dev2.as_ptr(),
+            password("dev2", unix_time() + 10).as_ptr()
+        ),
+        ::MOSQ_ERR_SUCCESS
+    );
+    assert_eq!(
+        ::proc_mosquitto_auth_acl_check_v2(
+            unsafe { *grace },
+            ::NULL,
+            dev1.as_ptr(),
+            topic.as_ptr(),
+            ::MOSQ_ACL_READ,
+        ),
+        ::MOSQ_ERR_SUCCESS
+    );
+
+    cleanup_plugin(unbound);
+    cleanup_plugin(grace);
+}
+
+#[test]
+fn test_client_id_binding() {
+    let ptr_user_data = init_plugin("acl10.json");
+    let client = client(0);
+    let user_data = unsafe { *ptr_user_data };
+    let token =
+        |sub: &str| CString::new(token(&json!({"sub": sub, "exp": unix_time() + 10}))).unwrap();
+    let auth = |client_id: &str, token: &CString| {
+        let client_id = CString::new(client_id).unwrap();
+        ::proc_mosquitto_auth_unpwd_check_v3(
+            user_data,
+            client,
+            client_id.as_ptr(),
+            token.as_ptr(),
+            ::NULL,
+        )
+    };
+
+    let dev = token("dev1");
+    assert_eq!(auth("dev1-sensor", &dev), ::MOSQ_ERR_SUCCESS);
+    assert_eq!(auth("dev1-", &dev), ::MOSQ_ERR_SUCCESS);
+    assert_eq!(auth("dev2-sensor", &dev), ::MOSQ_ERR_AUTH);
+    assert_eq!(auth("xdev1-sensor", &dev), ::MOSQ_ERR_AUTH);
+    assert_eq!(auth("dev1", &dev), ::MOSQ_ERR_AUTH);
+    // the claim is not a pattern
+    let dot = token("dev.");
+    assert_eq!(auth("dev.-1", &dot), ::MOSQ_ERR_SUCCESS);
+    assert_eq!(auth("devx-1", &dot), ::MOSQ_ERR_AUTH);
+
+    // the v2 interface checks the client id on ACL checks
+    let topic = CString::new("/mqtt_test").unwrap();
+    assert_eq!(
+        ::proc_mosquitto_auth_unpwd_check_v2(user_data, dev.as_ptr(), ::NULL),
+        ::MOSQ_ERR_SUCCESS
+    );
+    let acl_v2 = |client_id: &str| {
+        let client_id = CString::new(client_id).unwrap();
+        ::proc_mosquitto_auth_acl_check_v2(
+            user_data,
+            client_id.as_ptr(),
+            dev.as_ptr(),
+            topic.as_ptr(),
+            ::MOSQ_ACL_WRITE,
+        )
+    };
+    assert_eq!(acl_v2("dev1-sensor"), ::MOSQ_ERR_SUCCESS);
+    assert_eq!(acl_v2("dev2-sensor"), ::MOSQ_ERR_ACL_DENIED);
+
+    cleanup_plugin(ptr_user_data);
+}
+
+#[test]
+fn test_subscribe() {
+    let (client1, client2) = (client(0), client(1));
+    let claims = |sub| json!({"sub": sub, "exp": unix_time() + 10});
+
+    let ptr_user_data = init_plugin("acl11.json");
+    assert_eq!(
+        login(ptr_user_data, client1, claims("xxxx@example.jp")),
+        ::MOSQ_ERR_SUCCESS
+    );
+    assert_eq!(
+        login(ptr_user_data, client2, claims("yyyy@example.jp")),
+        ::MOSQ_ERR_SUCCESS
+    );
+    let subscribe = |client, topic| acl_v3(ptr_user_data, client, topic, ::MOSQ_ACL_SUBSCRIBE);
+    assert_eq!(subscribe(client1, "/mqtt_test"), ::MOSQ_ERR_SUCCESS);
+    assert_eq!(subscribe(client1, "/sensor/temp"), ::MOSQ_ERR_SUCCESS);
+    // READ does not grant the subscription
+    assert_eq!(subscribe(client2, "/sensor/temp"), ::MOSQ_ERR_ACL_DENIED);
+    assert_eq!(
+        acl_v3(ptr_user_data, client2, "/sensor/temp", ::MOSQ_ACL_READ),
+        ::MOSQ_ERR_SUCCESS
+    );
+    assert_eq!(subscribe(client1, "/command"), ::MOSQ_ERR_ACL_DENIED);
+    assert_eq!(subscribe(client1, "#"), ::MOSQ_ERR_ACL_DENIED);
+    assert_eq!(subscribe(client1, "/unknown"), ::MOSQ_ERR_ACL_DENIED);
+    cleanup_plugin(ptr_user_data);
+
+    // every resource can be subscribed with legacySubscribe
+    let ptr_user_data = init_plugin("acl12.json");
+    assert_eq!(
+        login(ptr_user_data, client2, claims("yyyy@example.jp")),
+        ::MOSQ_ERR_SUCCESS
+    );
+    let subscribe = |client, topic| acl_v3(ptr_user_data, client, topic, ::MOSQ_ACL_SUBSCRIBE);
+    assert_eq!(subscribe(client2, "/sensor/temp"), ::MOSQ_ERR_SUCCESS);
+    assert_eq!(subscribe(client2, "/command"), ::MOSQ_ERR_SUCCESS);
+    // but the subject and the effect still apply
+    assert_eq!(subscribe(client2, "/unknown"), ::MOSQ_ERR_ACL_DENIED);
+    assert_eq!(subscribe(client2, "/secret"), ::MOSQ_ERR_ACL_DENIED);
+    cleanup_plugin(ptr_user_data);
+}
+
+#[test]
+fn test_topic_filter() {
+    let ptr_user_data = init_plugin("acl13.json");
+    let (user, admin) = (client(0), client(1));
+    for (client, sub) in &[(user, "xxxx@example.jp"), (admin, "admin@example.jp")] {
+        let claims = json!({"sub": sub, "exp": unix_time() + 10});
+        assert_eq!(login(ptr_user_data, *client, claims), ::MOSQ_ERR_SUCCESS);
+    }
+    let check = |client, topic, access| acl_v3(ptr_user_data, client, topic, access) == 0;
+    let write = |topic| check(user, topic, ::MOSQ_ACL_WRITE);
+    let subscribe = |topic| check(user, topic, ::MOSQ_ACL_SUBSCRIBE);
+
+    // `+` matches exactly one level, which may be empty
+    assert!(write("/sensors/a/temp"));
+    assert!(write("/sensors//temp"));
+    assert!(!write("/sensors/temp"));
+    assert!(!write("/sensors/a/b/temp"));
+    assert!(!write("/sensors/a/temp/"));
+    assert!(subscribe("/sensors/+/temp"));
+    assert!(subscribe("/sensors/a/temp"));
+    assert!(!subscribe("/sensors/#"));
+    assert!(!subscribe("/sensors/+/+"));
+    assert!(!subscribe("/sensors/+/temp/#"));
+
+    // `#` matches the parent and any number of levels
+    assert!(write("/devices"));
+    assert!(write("/devices/"));
+    assert!(write("/devices/a/b"));
+    assert!(!write("/devicesx"));
+    assert!(subscribe("/devices"));
+    assert!(subscribe("/devices/#"));
+    assert!(subscribe("/devices/+/b"));
+    assert!(subscribe("/devices/a/#"));
+    assert!(!subscribe("/+/a"));
+    assert!(!subscribe("/dev/#"));
+
+    // a wildcard at the first level does not match `$` topics
+    assert!(write("a/status"));
+    assert!(write("/status"));
+    assert!(!write("$SYS/status"));
+    assert!(!write("a/b/status"));
+    assert!(subscribe("+/status"));
+    assert!(!subscribe("$SYS/status"));
+    assert!(!subscribe("#"));
+
+    // a plain path is the prefix of the topics
+    assert!(write("/mqtt_test"));
+    assert!(write("/mqtt_test/a"));
+    assert!(!write("/mqtt_testx"));
+    assert!(subscribe("/mqtt_test"));
+    assert!(subscribe("/mqtt_test/#"));
+    assert!(subscribe("/mqtt_test/+/a"));
+    assert!(!subscribe("/mqtt_testx"));
+    assert!(!subscribe("/+"));
+
+    assert!(check(admin, "a/b", ::MOSQ_ACL_READ));
+    assert!(check(admin, "#", ::MOSQ_ACL_SUBSCRIBE));
+    assert!(check(admin, "+/+", ::MOSQ_ACL_SUBSCRIBE));
+    assert!(!check(admin, "$SYS/broker/uptime", ::MOSQ_ACL_READ));
+    assert!(!check(admin, "$SYS/#", ::MOSQ_ACL_SUBSCRIBE));
+    assert!(!check(admin, "$SYS/+/uptime", ::MOSQ_ACL_SUBSCRIBE));
+
+    cleanup_plugin(ptr_user_data);
+}
+
+#[test]
+fn test_combining() {
+    let (staff, contractor, admin) = (client(0), client(1), client(2));
+    let payroll = "/m/d/payroll/transaction";
+    // (client, topic, access, firstApplicable, denyOverrides, permitOverrides)
+    let cases = [
+        (staff, payroll, ::MOSQ_ACL_READ, true, true, true),
+        (contractor, payroll, ::MOSQ_ACL_READ, true, false, true),
+        (contractor, payroll, ::MOSQ_ACL_WRITE, false, false, false),
+        (
+            contractor,
+            "/m/d/db2/transaction",
+            ::MOSQ_ACL_READ,
+            true,
+            true,
+            true,
+        ),
+        (staff, "/m/secret", ::MOSQ_ACL_WRITE, false, false, false),
+        (admin, "/m/secret", ::MOSQ_ACL_WRITE, false, false, true),
+        (admin, "/m/secret", ::MOSQ_ACL_READ, false, false, true),
+        (admin, "/m/public", ::MOSQ_ACL_WRITE, true, true, true),
+        (staff, "/m/public", ::MOSQ_ACL_READ, true, true, true),
+        (staff, "/m/public", ::MOSQ_ACL_WRITE, false, false, false),
+    ];
+    for (i, file_name) in ["acl14.json", "acl15.json", "acl16.json"]
+        .iter()
+        .enumerate()
+    {
+        let ptr_user_data = init_plugin(file_name);
+        for (client, role) in &[
+            (staff, "staff"),
+            (contractor, "contractor"),
+            (admin, "admin"),
+        ] {
+            let claims = json!({"sub": "xxxx@example.jp", "role": role, "exp": unix_time() + 10});
+            assert_eq!(login(ptr_user_data, *client, claims), ::MOSQ_ERR_SUCCESS);
+        }
+        for case in &cases {
+            let expected = [case.3, case.4, case.5][i];
+            assert_eq!(
+                acl_v3(ptr_user_data, case.0, case.1, case.2) == ::MOSQ_ERR_SUCCESS,
+                expected,
+                "{} {} {}",
+                file_name,
+                case.1,
+                case.2
+            );
+        }
+        cleanup_plugin(ptr_user_data);
+    }
+}
+
+#[test]
+fn test_path_template() {
+    let ptr_user_data = init_plugin("acl17.json");
+    let user_data = unsafe { *ptr_user_data };
+    let login = |i: usize, mut claims: serde_json::Value| {
+        claims["cid"] = json!(format!("c{}", i));
+        let token = CString::new(token(&claims)).unwrap();
+        let client_id = CString::new(format!("c{}", i)).unwrap();
+        ::proc_mosquitto_auth_unpwd_check_v3(
+            user_data,
+            client(i),
+            client_id.as_ptr(),
+            token.as_ptr(),
+            ::NULL,
+        )
+    };
+    let exp = unix_time() + 10;
+    assert_eq!(
+        login(0, json!({"sub": "alice", "tenant": "t1", "exp": exp})),
+        ::MOSQ_ERR_SUCCESS
+    );
+    assert_eq!(
+        login(1, json!({"sub": "a.*", "tenant": "t.*", "exp": exp})),
+        ::MOSQ_ERR_SUCCESS
+    );
+    assert_eq!(
+        login(2, json!({"sub": "x/+", "tenant": "#", "exp": exp})),
+        ::MOSQ_ERR_SUCCESS
+    );
+    assert_eq!(
+        login(3, json!({"sub": "bob", "exp": exp})),
+        ::MOSQ_ERR_SUCCESS
+    );
+    let check = |i, topic, access| acl_v3(ptr_user_data, client(i), topic, access) == 0;
+    let write = |i, topic| check(i, topic, ::MOSQ_ACL_WRITE);
+    let subscribe = |i, topic| check(i, topic, ::MOSQ_ACL_SUBSCRIBE);
+
+    assert!(write(0, "/users/alice/x"));
+    assert!(!write(0, "/users/bob/x"));
+    assert!(subscribe(0, "/users/alice/#"));
+    assert!(!subscribe(0, "/users/+/x"));
+    assert!(write(0, "/tenants/t1/a/data"));
+    assert!(!write(0, "/tenants/t2/a/data"));
+    assert!(write(0, "/clients/c0"));
+    assert!(!write(0, "/clients/c1"));
+    assert!(write(0, "/r/alice/12"));
+    assert!(!write(0, "/r/alice/x"));
+    assert!(check(0, "/m/d/t1/transaction", ::MOSQ_ACL_READ));
+    assert!(!check(0, "/m/d/t2/transaction", ::MOSQ_ACL_READ));
+    assert!(write(0, "/m/d/tenant-t1/subset/sub1/transaction"));
+    assert!(!write(0, "/m/d/tenant-t2/subset/sub1/transaction"));
+
+    // a value is a literal in a regex
+    assert!(write(1, "/r/a.*/12"));
+    assert!(!write(1, "/r/abc/12"));
+    assert!(!write(1, "/m/d/tenant-tx/subset/sub1/transaction"));
+    assert!(write(1, "/m/d/tenant-t.*/subset/sub1/transaction"));
+
+    // a value cannot add topic levels or wildcards
+    assert!(!write(2, "/users/x/y/z"));
+    assert!(!subscribe(2, "/users/x/+/#"));
+    assert!(!subscribe(2, "/users/#"));
+    assert!(!write(2, "/tenants/a/b/data"));
+    assert!(!subscribe(2, "/tenants/#"));
+
+    // a missing claim matches nothing
+    assert!(write(3, "/users/bob/x"));
+    assert!(!write(3, "/tenants//a/data"));
+    assert!(!check(3, "/m/d/transaction", ::MOSQ_ACL_READ));
+
+    cleanup_plugin(ptr_user_data);
+}
+
+#[test]
+fn test_path_captures() {
+    let ptr_user_data = init_plugin("acl18.json");
+    for (i, tenant) in ["t1", "t."].iter().enumerate() {
+        assert_eq!(
+            login(
+                ptr_user_data,
+                client(i),
+                json!({
+                    "sub": "xxxx@example.jp",
+                    "tenant": tenant,
+                    "dept": "sales",
+                    "exp": unix_time() + 10,
+                })
+            ),
+            ::MOSQ_ERR_SUCCESS
+        );
+    }
+    let write = |i, topic| acl_v3(ptr_user_data, client(i), topic, ::MOSQ_ACL_WRITE) == 0;
+
+    assert!(write(0, "/tenants/t1/x"));
+    assert!(!write(0, "/tenants/t2/x"));
+    assert!(write(0, "/m/d/t1-db/subset/sales/transaction"));
+    assert!(!write(0, "/m/d/t2-db/subset/sales/transaction"));
+    assert!(!write(0, "/m/d/t1-db/subset/dev/transaction"));
+    assert!(!write(0, "/m/d/t1-db/transaction"));
+    // the claim is compared literally
+    assert!(write(1, "/tenants/t./x"));
+    assert!(!write(1, "/tenants/tx/x"));
+    assert!(!write(1, "/m/d/tx-db/subset/sales/transaction"));
+
+    cleanup_plugin(ptr_user_data);
+}
+
+#[test]
+fn test_claim_conditions() {
+    let ptr_user_data = init_plugin("acl19.json");
+    let claims = [
+        json!({
+            "sub": "xxxx@example.jp",
+            "realm_access": {"roles": ["viewer", "operator"]},
+            "org": {"tenant": "t1", "id": 42},
+            "email_verified": true,
+            "level": 4,
+            "https://example.jp/groups": ["users", "admins"],
+            "exp": unix_time() + 10,
+        }),
+        json!({
+            "sub": "yyyy@example.jp",
+            "realm_access": {"roles": ["viewer"]},
+            "org": {"tenant": "t2", "id": "42"},
+            "email_verified": "true",
+            "level": 6,
+            "https://example.jp/groups": "users",
+            "exp": unix_time() + 10,
+        }),
+    ];
+    for (i, claims) in claims.iter().enumerate() {
+        assert_eq!(
+            login(ptr_user_data, client(i), claims.clone()),
+            ::MOSQ_ERR_SUCCESS
+        );
+    }
+    let write = |i, topic| acl_v3(ptr_user_data, client(i), topic, ::MOSQ_ACL_WRITE) == 0;
+
+    // any element of an array
+    assert!(write(0, "/roles"));
+    assert!(!write(1, "/roles"));
+    assert!(write(0, "/pointer"));
+    assert!(!write(1, "/pointer"));
+    assert!(write(0, "/flags"));
+    assert!(!write(1, "/flags"));
+    assert!(write(0, "/levels"));
+    assert!(!write(1, "/levels"));
+    assert!(write(0, "/equals"));
+    assert!(!write(1, "/equals"));
+    // a number is not matched by a regex
+    assert!(!write(0, "/typed"));
+    // a claim named with dots
+    assert!(write(0, "/url"));
+    assert!(!write(1, "/url"));
+
+    cleanup_plugin(ptr_user_data);
+}
+
+#[test]
+fn test_subject_expression() {
+    let ptr_user_data = init_plugin("acl20.json");
+    let users = [
+        ("admin", "Y"),
+        ("operator", "X"),
+        ("operator", "Y"),
+        ("contractor", "X"),
+    ];
+    for (i, (role, site)) in users.iter().enumerate() {
+        assert_eq!(
+            login(
+                ptr_user_data,
+                client(i),
+                json!({"sub": "xxxx@example.jp", "role": role, "site": site, "exp": unix_time() + 10})
+            ),
+            ::MOSQ_ERR_SUCCESS
+        );
+    }
+    let write = |i, topic| acl_v3(ptr_user_data, client(i), topic, ::MOSQ_ACL_WRITE) == 0;
+
+    assert!(write(0, "/ops"));
+    assert!(write(1, "/ops"));
+    assert!(!write(2, "/ops"));
+    assert!(!write(3, "/ops"));
+    assert!(write(0, "/staff"));
+    assert!(write(2, "/staff"));
+    assert!(!write(3, "/staff"));
+
+    cleanup_plugin(ptr_user_data);
+}
+
+#[test]
+fn test_scope() {
+    let ptr_user_data = init_plugin("acl21.json");
+    let scopes = [
+        json!(
+            "openid mqtt:read:/telemetry mqtt:subscribe:/telemetry/# mqtt:write:/cmd/+ \
+               other:write:/x mqtt:write:/cmd/locked mqtt:delete:/y"
+        ),
+        json!(["mqtt:write:/x"]),
+    ];
+    for (i, scope) in scopes.iter().enumerate() {
+        assert_eq!(
+            login(
+                ptr_user_data,
+                client(i),
+                json!({"sub": "xxxx@example.jp", "scope": scope, "exp": unix_time() + 10})
+            ),
+            ::MOSQ_ERR_SUCCESS
+        );
+    }
+    let check = |i, topic, access| acl_v3(ptr_user_data, client(i), topic, access) == 0;
+
+    assert!(check(0, "/telemetry", ::MOSQ_ACL_READ));
+    assert!(!check(0, "/telemetry", ::MOSQ_ACL_WRITE));
+    assert!(check(0, "/telemetry/#", ::MOSQ_ACL_SUBSCRIBE));
+    // a topic filter rather than a prefix
+    assert!(!check(0, "/telemetry/a", ::MOSQ_ACL_READ));
+    assert!(!check(0, "/telemetryx", ::MOSQ_ACL_READ));
+    assert!(!check(0, "#", ::MOSQ_ACL_SUBSCRIBE));
+    assert!(check(0, "/cmd/dev1", ::MOSQ_ACL_WRITE));
+    assert!(!check(0, "/cmd/dev1/x", ::MOSQ_ACL_WRITE));
+    // another prefix, an unknown operation and a denied resource
+    assert!(!check(0, "/x", ::MOSQ_ACL_WRITE));
+    assert!(!check(0, "/y", ::MOSQ_ACL_WRITE));
+    assert!(!check(0, "/cmd/locked", ::MOSQ_ACL_WRITE));
+    // an array of scopes
+    assert!(check(1, "/x", ::MOSQ_ACL_WRITE));
+    assert!(!check(1, "/x/y", ::MOSQ_ACL_WRITE));
+    assert!(!check(1, "/cmd/dev1", ::MOSQ_ACL_WRITE));
+
+    cleanup_plugin(ptr_user_data);
+}
+
+#[test]
+fn test_token_acl() {
+    let ptr_user_data = init_plugin("acl22.json");
+    let claims = [
+        json!({
+            "sub": "xxxx@example.jp",
+            "mqtt_acl": [
+                {"topic": "/telemetry/dev1/#", "ops": ["READ", "SUBSCRIBE"]},
+                {"topic": "/admin/#", "ops": ["*"]},
+            ],
+            "exp": unix_time() + 10,
+        }),
+        json!({"sub": "xxxx@example.jp", "exp": unix_time() + 10}),
+        json!({"sub": "xxxx@example.jp", "mqtt_acl": "/telemetry", "exp": unix_time() + 10}),
+    ];
+    for (i, claims) in claims.iter().enumerate() {
+        assert_eq!(
+            login(ptr_user_data, client(i), claims.clone()),
+            ::MOSQ_ERR_SUCCESS
+        );
+    }
+    let check = |i, topic, access| acl_v3(ptr_user_data, client(i), topic, access) == 0;
+
+    // narrowed by the token
+    assert!(check(0, "/telemetry/dev1/temp", ::MOSQ_ACL_READ));
+    assert!(!check(0, "/telemetry/dev1/temp", ::MOSQ_ACL_WRITE));
+    assert!(!check(0, "/telemetry/dev2/temp", ::MOSQ_ACL_READ));
+    assert!(check(0, "/telemetry/dev1/#", ::MOSQ_ACL_SUBSCRIBE));
+    assert!(!check(0, "/telemetry/#", ::MOSQ_ACL_SUBSCRIBE));
+    // never widened
+    assert!(!check(0, "/admin/x", ::MOSQ_ACL_WRITE));
+    // without the claim
+    assert!(check(1, "/telemetry/dev2/temp", ::MOSQ_ACL_WRITE));
+    // an illegal claim
+    assert!(!check(2, "/telemetry/dev1/temp", ::MOSQ_ACL_READ));
+
+    cleanup_plugin(ptr_user_data);
+}
+
+#[test]
+fn test_roles() {
+    let ptr_user_data = init_plugin("acl23.json");
+    let claims = [
+        json!({"sub": "xxxx@example.jp", "realm_access": {"roles": ["operator"]}, "site": "X"}),
+        json!({"sub": "yyyy@example.jp", "realm_access": {"roles": ["operator"]}, "site": "Y"}),
+        json!({"sub": "zzzz@example.jp", "groups": ["users", "admins"]}),
+        json!({"sub": "wwww@example.jp", "site": "X"}),
+    ];
+    for (i, claims) in claims.iter().enumerate() {
+        let mut claims = claims.clone();
+        claims["exp"] = json!(unix_time() + 10);
+        assert_eq!(login(ptr_user_data, client(i), claims), ::MOSQ_ERR_SUCCESS);
+    }
+    let write = |i, topic| acl_v3(ptr_user_data, client(i), topic, ::MOSQ_ACL_WRITE) == 0;
+
+    assert!(write(0, "/ops"));
+    assert!(!write(0, "/admin"));
+    assert!(write(0, "/site"));
+    assert!(write(1, "/ops"));
+    assert!(!write(1, "/site"));
+    assert!(write(2, "/ops"));
+    assert!(write(2, "/admin"));
+    assert!(!write(2, "/site"));
+    assert!(!write(3, "/ops"));
+    assert!(!write(3, "/site"));
+
+    cleanup_plugin(ptr_user_data);
+}
+
+#[test]
+fn test_directory() {
+    for file_name in &["acl24.json", "acl25.json"] {
+        let ptr_user_data = init_plugin(file_name);
+        let claims = [
+            json!({"sub": "xxxx@example.jp"}),
+            json!({"sub": "yyyy@example.jp", "groups": ["hr"]}),
+            json!({"sub": "zzzz@example.jp", "groups": ["hr"]}),
+        ];
+        for (i, claims) in claims.iter().enumerate() {
+            let mut claims = claims.clone();
+            claims["exp"] = json!(unix_time() + 10);
+            assert_eq!(login(ptr_user_data, client(i), claims), ::MOSQ_ERR_SUCCESS);
+        }
+        let write = |i, topic| acl_v3(ptr_user_data, client(i), topic, ::MOSQ_ACL_WRITE) == 0;
+
+        assert!(write(0, "/hr"), "{}", file_name);
+        assert!(write(0, "/sales"), "{}", file_name);
+        // the directory overrides the claims, except the registered ones
+        assert!(!write(1, "/hr"), "{}", file_name);
+        assert!(!write(1, "/sales"), "{}", file_name);
+        assert!(write(1, "/users/yyyy@example.jp"), "{}", file_name);
+        assert!(!write(1, "/users/hr@example.jp"), "{}", file_name);
+        // a subject which is not in the directory
+        assert!(write(2, "/hr"), "{}", file_name);
+
+        cleanup_plugin(ptr_user_data);
+    }
+}
+
+#[test]
+fn test_publish_constraints() {
+    let ptr_user_data = init_plugin("acl26.json");
+    for (i, role) in ["user", "admin"].iter().enumerate() {
+        assert_eq!(
+            login(
+                ptr_user_data,
+                client(i),
+                json!({"sub": "xxxx@example.jp", "role": role, "exp": unix_time() + 10})
+            ),
+            ::MOSQ_ERR_SUCCESS
+        );
+    }
+    let publish = |i, payloadlen, qos, retain| {
+        let payload = vec![0u8; payloadlen];
+        publish_v3(ptr_user_data, client(i), "/config/a", &payload, qos, retain) == 0
+    };
+
+    assert!(publish(0, 16, 1, false));
+    assert!(!publish(0, 17, 1, false));
+    assert!(!publish(0, 16, 2, false));
+    assert!(!publish(0, 16, 1, true));
+    // only admins may set retained messages
+    assert!(publish(1, 1024, 2, true));
+    // constraints are for publishing
+    assert!(acl_v3(ptr_user_data, client(0), "/config/a", ::MOSQ_ACL_READ) == 0);
+
+    cleanup_plugin(ptr_user_data);
+}
+
+#[test]
+fn test_transaction_rule() {
+    let ptr_user_data = init_plugin("acl27.json");
+    for (i, role) in ["writer", "admin"].iter().enumerate() {
+        assert_eq!(
+            login(
+                ptr_user_data,
+                client(i),
+                json!({"sub": "xxxx@example.jp", "role": role, "exp": unix_time() + 10})
+            ),
+            ::MOSQ_ERR_SUCCESS
+        );
+    }
+    let publish = |i, payload: &[u8]| {
+        publish_v3(
+            ptr_user_data,
+            client(i),
+            "/m/d/orders/transaction",
+            payload,
+            1,
+            false,
+        ) == 0
+    };
+    let write = |i, transaction: serde_json::Value| publish(i, transaction.to_string().as_bytes());
+    let mine = json!({"_id": "1", "owner": "xxxx@example.jp"});
+    let others = json!({"_id": "2", "owner": "yyyy@example.jp"});
+
+    assert!(write(
+        0,
+        json!({"type": "insert", "target": "1", "new": mine})
+    ));
+    assert!(write(
+        0,
+        json!({"type": "update", "target": "1", "before": mine, "new": mine})
+    ));
+    // another owner
+    assert!(!write(
+        0,
+        json!({"type": "insert", "target": "2", "new": others})
+    ));
+    assert!(!write(
+        0,
+        json!({"type": "update", "target": "2", "before": others, "new": mine})
+    ));
+    // an update as Dadget sends it, without the new document
+    assert!(write(
+        0,
+        json!({"type": "update", "target": "1", "before": mine, "operator": {"$set": {"note": "x"}}})
+    ));
+    assert!(!write(
+        0,
+        json!({"type": "update", "target": "1", "before": mine, "operator": {"$set": {"owner": "yyyy@example.jp"}}})
+    ));
+    assert!(!write(
+        0,
+        json!({"type": "update", "target": "1", "before": mine, "operator": {"$unset": {"owner": ""}}})
+    ));
+    assert!(!write(
+        0,
+        json!({"type": "update", "target": "1", "before": mine, "operator": {"$rename": {"note": "owner"}}})
+    ));
+    assert!(!write(
+        0,
+        json!({"type": "update", "target": "1", "operator": {"$set": {"note": "x"}}})
+    ));
+    // another type
+    assert!(!write(
+        0,
+        json!({"type": "delete", "target": "1", "before": mine})
+    ));
+    assert!(!write(0, json!({"type": "truncate"})));
+    assert!(!publish(0, b"not json"));
+    assert!(write(1, json!({"type": "truncate"})));
+
+    cleanup_plugin(ptr_user_data);
+}
+
+#[test]
+fn test_dadget_topics() {
+    let ptr_user_data = init_plugin("acl28.json");
+    assert_eq!(
+        login(
+            ptr_user_data,
+            client(0),
+            json!({
+                "sub": "xxxx@example.jp",
+                "tenant": "t1",
+                "dept": "sales",
+                "exp": unix_time() + 10,
+            })
+        ),
+        ::MOSQ_ERR_SUCCESS
+    );
+    let check = |topic, access| acl_v3(ptr_user_data, client(0), topic, access) == 0;
+
+    assert!(check("/app/t1/db/db1/transaction", ::MOSQ_ACL_READ));
+    assert!(check("/app/t1/db/db1/items/transaction", ::MOSQ_ACL_READ));
+    assert!(!check("/app/t2/db/db1/transaction", ::MOSQ_ACL_READ));
+    // segments are percent-decoded
+    assert!(check("/app/t%31/db/db1/transaction", ::MOSQ_ACL_READ));
+    assert!(check(
+        "/app/t1/db/db1/sales_2024/transaction",
+        ::MOSQ_ACL_WRITE
+    ));
+    assert!(!check(
+        "/app/t1/db/db1/dev_2024/transaction",
+        ::MOSQ_ACL_WRITE
+    ));
+    // no table segment
+    assert!(!check("/app/t1/db/db1/transaction", ::MOSQ_ACL_WRITE));
+    // the database is the first positional segment
+    assert!(check("/app/t2/db/orders/transaction", ::MOSQ_ACL_WRITE));
+    assert!(check(
+        "/app/t2/db/orders/items/transaction",
+        ::MOSQ_ACL_WRITE
+    ));
+    // the default layouts are replaced
+    assert!(!check("/m/d/orders/transaction", ::MOSQ_ACL_WRITE));
+
+    cleanup_plugin(ptr_user_data);
+}
+
+#[test]
+fn test_invalid_config() {
+    // an unknown resource type is an error, rather than a rule which is ignored
+    let acl_file = std::env::current_dir()
+        .unwrap()
+        .join("tests/fixtures/acl29.json");
+    let ptr_user_data = init_plugin_at(&acl_file, None);
+    let token = token(&json!({
+        "sub": "xxxx@example.jp",
+        "exp": unix_time() + 10,
+    }));
+    assert_ne!(auth(ptr_user_data, &token), ::MOSQ_ERR_SUCCESS);
+
+    cleanup_plugin(ptr_user_data);
+}
+
+#[test]
+fn test_config_errors() {
+    let error = |file_name: &str| {
+        let e = check_config(&format!("tests/fixtures/{}", file_name)).unwrap_err();
+        let e = e.downcast::<ConfigError>().unwrap();
+        (e.path, e.acl.unwrap())
+    };
+    let (path, acl) = error("acl29.json");
+    assert_eq!(
+        (path.as_str(), acl.as_str()),
+        ("acl[1].resource.type", "events")
+    );
+    let (path, acl) = error("acl32.json");
+    assert_eq!(
+        (path.as_str(), acl.as_str()),
+        ("acl[0].resource.path", "orders")
+    );
+    let (path, acl) = error("acl33.json");
+    assert_eq!(
+        (path.as_str(), acl.as_str()),
+        ("acl[0].accesses[0].subject.anyOf[1].level", "telemetry")
+    );
+
+    let warnings: Vec<_> = check_config("tests/fixtures/acl34.json")
+        .unwrap()
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+    assert_eq!(
+        warnings,
+        [
+            "acl[0].accesses (acl unused): no accesses",
+            "acl[1].resource.path (acl broken): regex never matches: ^/a$/b",
+            "acl[2].resource.path (acl inbox): ${clientid} without clientIdBinding",
+            "acl[2].accesses[0].subject (acl inbox): ${username} without usernameClaim",
+        ]
+    );
+}
+
+#[test]
+fn test_samples_load() {
+    // the configs which fail to load are in tests/fixtures
+    for entry in std::fs::read_dir("samples").unwrap() {
+        let path = entry.unwrap().path();
+        let path = path.to_str().unwrap();
+        if path.starts_with("samples/acl") && path.ends_with(".json") {
+            assert!(check_config(path).is_ok(), "{}", path);
+        }
+    }
+}
+
+#[test]
+fn test_reload_keeps_policy() {
+    let dir = std::env::temp_dir().join(format!("chipin-reload-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let acl_file = dir.join("acl.json");
+    std::fs::copy("samples/acl.json", &acl_file).unwrap();
+    let status_file = dir.join("status.json");
+    let ptr_user_data = init_plugin_at(&acl_file, Some(&status_file));
+    let reload =
+        || ::proc_mosquitto_auth_security_init(unsafe { *ptr_user_data }, std::ptr::null(), 0, 1);
+    let token = token(&json!({
+        "sub": "xxxx@example.jp",
+        "exp": unix_time() + 10,
+    }));
+    let (version, status) = config_status(&status_file);
+    assert!(version > 0);
+    assert!(status["hash"].is_string());
+    assert!(status["lastFailure"].is_null());
+    assert_eq!(auth(ptr_user_data, &token), ::MOSQ_ERR_SUCCESS);
+
+    // a typo does not take the policy down
+    std::fs::write(&acl_file, format!("{{\"key\": \"{}\", \"acl\": [", SECRET)).unwrap();
+    assert_eq!(reload(), ::MOSQ_ERR_SUCCESS);
+    let (failed_version, failed) = config_status(&status_file);
+    assert_eq!(failed_version, version);
+    assert_eq!(failed["hash"], status["hash"]);
+    assert!(failed["lastFailure"].is_string());
+    assert_eq!(auth(ptr_user_data, &token), ::MOSQ_ERR_SUCCESS);
+
+    // SIGHUP loads a valid file at once
+    std::fs::write(&acl_file, "{\"key\": \"another-key\", \"acl\": []}").unwrap();
+    assert_eq!(reload(), ::MOSQ_ERR_SUCCESS);
+    let (reloaded_version, reloaded) = config_status(&status_file);
+    assert!(reloaded_version > version);
+    assert_ne!(reloaded["hash"], status["hash"]);
+    assert_eq!(reloaded["lastFailure"], failed["lastFailure"]);
+    assert_ne!(auth(ptr_user_data, &token), ::MOSQ_ERR_SUCCESS);
+
+    cleanup_plugin(ptr_user_data);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_watch_config() {
+    use std::os::unix::fs::symlink;
+
+    // the layout of a ConfigMap of Kubernetes
+    let dir = std::env::temp_dir().join(format!("chipin-watch-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("..v1")).unwrap();
+    std::fs::create_dir_all(dir.join("..v2")).unwrap();
+    std::fs::copy("samples/acl.json", dir.join("..v1/acl.json")).unwrap();
+    std::fs::copy("samples/acl.json", dir.join("..v2/acl.json")).unwrap();
+    symlink("..v1", dir.join("..data")).unwrap();
+    symlink("..data/acl.json", dir.join("acl.json")).unwrap();
+    let status_file = dir.join("status.json");
+    let ptr_user_data = init_plugin_at(&dir.join("acl.json"), Some(&status_file));
+    let wait_for_reload = |version| {
+        let start = Instant::now();
+        while config_status(&status_file).0 == version && start.elapsed().as_secs() < 5 {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        config_status(&status_file).0
+    };
+    let token = token(&json!({
+        "sub": "xxxx@example.jp",
+        "exp": unix_time() + 10,
+    }));
+    let version = config_status(&status_file).0;
+    assert_eq!(auth(ptr_user_data, &token), ::MOSQ_ERR_SUCCESS);
+
+    // a file renamed over the target
+    let tmp = dir.join("..v1/acl.json.tmp");
+    std::fs::write(&tmp, "{\"key\": \"another-key\", \"acl\": []}").unwrap();
+    std::fs::rename(&tmp, dir.join("..v1/acl.json")).unwrap();
+    let version = {
+        let reloaded = wait_for_reload(version);
+        assert_ne!(reloaded, version);
+        reloaded
+    };
+    assert_ne!(auth(ptr_user_data, &token), ::MOSQ_ERR_SUCCESS);
+
+    // a swapped link to the directory
+    symlink("..v2", dir.join("..data_tmp")).unwrap();
+    std::fs::rename(dir.join("..data_tmp"), dir.join("..data")).unwrap();
+    assert_ne!(wait_for_reload(version), version);
+    assert_eq!(auth(ptr_user_data, &token), ::MOSQ_ERR_SUCCESS);
+
+    cleanup_plugin(ptr_user_data);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_watch_linked_files() {
+    let dir = std::env::temp_dir().join(format!("chipin-linked-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::copy("samples/acl.json", dir.join("acl.json")).unwrap();
+    let status_file = dir.join("status.json");
+    let ptr_user_data = init_plugin_at(&dir.join("acl.json"), Some(&status_file));
+    let wait_for = |done: &dyn Fn(&serde_json::Value) -> bool| {
+        let start = Instant::now();
+        while !done(&config_status(&status_file).1) && start.elapsed().as_secs() < 5 {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        config_status(&status_file).1
+    };
+    let (version, _) = config_status(&status_file);
+
+    // a revocation list named before it is created
+    std::fs::copy("samples/acl8.json", dir.join("acl.json")).unwrap();
+    let failed = wait_for(&|x| x["lastFailure"].is_string());
+    assert_eq!(failed["version"], json!(version));
+    std::fs::copy("samples/revocation.json", dir.join("revocation.json")).unwrap();
+    let reloaded = wait_for(&|x| x["version"] != json!(version));
+    assert_ne!(reloaded["version"], json!(version));
+
+    cleanup_plugin(ptr_user_data);
+    std::fs::remove_dir_all(&dir).unwrap();
+}